@@ -0,0 +1,3 @@
+-- Add up migration script here
+
+alter table accounts add column version int8 not null default 0;
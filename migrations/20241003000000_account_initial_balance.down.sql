@@ -0,0 +1,6 @@
+-- Add down migration script here
+
+alter table transaction
+    alter constraint transaction_to_address_fkey not deferrable;
+
+alter table accounts drop column initial_balance;
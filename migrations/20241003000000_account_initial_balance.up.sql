@@ -0,0 +1,9 @@
+-- Add up migration script here
+
+-- Accounts created by a transfer start out empty, seeded accounts set this explicitly
+alter table accounts add column initial_balance int8 not null default 0;
+
+-- good_transfer records the transaction before it creates the recipient account,
+-- so the recipient only has to exist by the time the transaction commits
+alter table transaction
+    alter constraint transaction_to_address_fkey deferrable initially deferred;
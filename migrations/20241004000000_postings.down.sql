@@ -0,0 +1,5 @@
+-- Add down migration script here
+
+drop trigger postings_balanced on postings;
+drop function postings_balanced();
+drop table postings;
@@ -0,0 +1,32 @@
+-- Add up migration script here
+
+-- Double-entry journal: every transaction is split into postings that sum to zero,
+-- a debit (positive amount) on the recipient and a credit (negative amount) on the sender
+create table postings(
+    id bigserial primary key,
+    tx_hash varchar(64) not null references transaction(tx_hash),
+    address varchar(35) not null references accounts(address) deferrable initially deferred,
+    amount int8 not null,
+    created_at timestamp not null default current_timestamp
+);
+
+create index idx_postings_tx_hash on postings(tx_hash);
+create index idx_postings_address on postings(address);
+
+create function postings_balanced() returns trigger as $$
+declare
+    hash varchar(64) := coalesce(new.tx_hash, old.tx_hash);
+begin
+    if (select coalesce(sum(amount), 0) from postings where tx_hash = hash) <> 0 then
+        raise exception 'postings of transaction % do not balance', hash
+            using errcode = 'check_violation';
+    end if;
+    return null;
+end;
+$$ language plpgsql;
+
+-- Checked at commit, so the postings of a transaction may be written one at a time
+create constraint trigger postings_balanced
+    after insert or update or delete on postings
+    deferrable initially deferred
+    for each row execute function postings_balanced();
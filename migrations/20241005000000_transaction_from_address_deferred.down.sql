@@ -0,0 +1,4 @@
+-- Add down migration script here
+
+alter table transaction
+    alter constraint transaction_from_address_fkey not deferrable;
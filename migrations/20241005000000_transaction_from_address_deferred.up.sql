@@ -0,0 +1,6 @@
+-- Add up migration script here
+
+-- Transfers record the transaction first to claim its tx_hash, before they look at the sender,
+-- so a missing sender is reported by the transfer rather than by this constraint
+alter table transaction
+    alter constraint transaction_from_address_fkey deferrable initially deferred;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+
+drop trigger bump_account_version on accounts;
+drop function bump_account_version();
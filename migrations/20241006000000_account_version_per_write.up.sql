@@ -0,0 +1,13 @@
+-- Add up migration script here
+
+create function bump_account_version() returns trigger as $$
+begin
+    new.version := old.version + 1;
+    return new;
+end;
+$$ language plpgsql;
+
+-- Every committed balance is a version of its own, so histories can order the writes to an account
+create trigger bump_account_version
+    before update of balance on accounts
+    for each row execute function bump_account_version();
@@ -0,0 +1,312 @@
+use std::{collections::HashMap, fmt};
+
+use crate::history::{Access, Completion, Operation};
+
+/// An interleaving in a recorded history that no serial execution of the same transfers allows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Anomaly {
+    /// The transfers all read `balance` at `version` from `address`, each wrote it back based on
+    /// that read, and all committed. Only one of them saw the version it actually replaced
+    LostUpdate { address: String, balance: i64, version: i64, tx_hashes: Vec<String> },
+    /// `tx_hash` read `read` from `address`, but by the time it wrote the account the balance
+    /// was `overwritten`, installed by `writer` if it is in the history
+    NonRepeatableRead { tx_hash: String, address: String, read: i64, overwritten: i64, writer: Option<String> },
+    /// Running concurrently, each transfer read an account the other one wrote without
+    /// writing it itself, so neither saw the other's write
+    WriteSkew { tx_hashes: [String; 2], addresses: [String; 2] },
+    /// `tx_hash` saw the write of `writer` to one of `addresses` but not to the other
+    ReadSkew { tx_hash: String, writer: String, addresses: [String; 2] },
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anomaly::LostUpdate { address, balance, version, tx_hashes } => {
+                write!(f, "Lost update on {}: {} all read {} at version {} and committed", address, tx_hashes.join(", "), balance, version)
+            }
+            Anomaly::NonRepeatableRead { tx_hash, address, read, overwritten, writer } => {
+                write!(f, "Non-repeatable read of {} by {}: read {} but wrote over {}", address, tx_hash, read, overwritten)?;
+                if let Some(writer) = writer {
+                    write!(f, " written by {}", writer)?;
+                }
+                Ok(())
+            }
+            Anomaly::WriteSkew { tx_hashes: [a, b], addresses: [x, y] } => {
+                write!(f, "Write skew between {} and {}: {} read {} written by {}, {} read {} written by {}", a, b, a, x, b, b, y, a)
+            }
+            Anomaly::ReadSkew { tx_hash, writer, addresses: [x, y] } => {
+                write!(f, "Read skew in {}: saw {}'s write to {} but not to {}", tx_hash, writer, x, y)
+            }
+        }
+    }
+}
+
+/// Balance a write of `op` replaced. Transfers only ever add to or subtract from a balance, so
+/// it follows from the balance written
+fn overwritten(op: &Operation, address: &str, written: i64) -> i64 {
+    if address == op.from {
+        written + op.amount as i64
+    } else {
+        written - op.amount as i64
+    }
+}
+
+fn write_of<'a>(op: &'a Operation, address: &str) -> Option<&'a Access> {
+    op.writes.iter().find(|w| w.address == address)
+}
+
+fn concurrent(a: &Operation, b: &Operation) -> bool {
+    a.invoked_ns < b.completed_ns && b.invoked_ns < a.completed_ns
+}
+
+/// Looks for lost updates, non-repeatable reads, write skew and read skew among the committed
+/// operations of `history`. Reads and writes are matched by the version of the account, which
+/// every committed write moves on by one, and a candidate is only reported when the
+/// transactions involved overlap in time
+pub fn detect(history: &[Operation]) -> Vec<Anomaly> {
+    let committed: Vec<&Operation> = history.iter().filter(|op| op.result == Completion::Commit).collect();
+    let mut writers: HashMap<&str, Vec<&Operation>> = HashMap::new();
+    for op in &committed {
+        for w in &op.writes {
+            writers.entry(&w.address).or_default().push(op);
+        }
+    }
+
+    let mut anomalies = Vec::new();
+
+    // A read is stale when the write that follows it replaced a later version than the one read
+    let mut stale = Vec::new();
+    let mut read_then_written: HashMap<(&str, i64), Vec<&Operation>> = HashMap::new();
+    for op in &committed {
+        for r in &op.reads {
+            let Some(written) = write_of(op, &r.address) else { continue };
+            read_then_written.entry((&r.address, r.version)).or_default().push(op);
+            if written.version - 1 != r.version {
+                stale.push((*op, r, written));
+            }
+        }
+    }
+
+    // Only transactions that ran at the same time as another reader of the version lost an update
+    // to each other. Report them in the order they happened
+    let mut read_then_written: Vec<_> = read_then_written
+        .into_iter()
+        .map(|(key, ops)| {
+            let mut ops: Vec<&Operation> =
+                ops.iter().copied().filter(|a| ops.iter().any(|b| !std::ptr::eq(*a, *b) && concurrent(a, b))).collect();
+            ops.sort_by_key(|op| op.invoked_ns);
+            (key, ops)
+        })
+        .filter(|(_, ops)| ops.len() > 1)
+        .collect();
+    read_then_written.sort_by_key(|(_, ops)| ops[0].invoked_ns);
+
+    let mut lost = Vec::new();
+    for ((address, version), ops) in &read_then_written {
+        let has_stale = ops.iter().any(|op| stale.iter().any(|(s, r, _)| std::ptr::eq(*s, *op) && r.address == *address));
+        if has_stale {
+            lost.extend(ops.iter().map(|op| (*op, *address)));
+            let balance = ops[0].reads.iter().find(|r| r.address == *address).expect("grouped by their reads").balance;
+            anomalies.push(Anomaly::LostUpdate {
+                address: address.to_string(),
+                balance,
+                version: *version,
+                tx_hashes: ops.iter().map(|op| op.tx_hash.clone()).collect(),
+            });
+        }
+    }
+
+    for (op, read, written) in stale {
+        if lost.iter().any(|(cited, address)| std::ptr::eq(*cited, op) && *address == read.address) {
+            continue;
+        }
+        let writer = writers[read.address.as_str()]
+            .iter()
+            .find(|w| write_of(w, &read.address).is_some_and(|w| w.version == written.version - 1))
+            .map(|w| w.tx_hash.clone());
+        anomalies.push(Anomaly::NonRepeatableRead {
+            tx_hash: op.tx_hash.clone(),
+            address: read.address.clone(),
+            read: read.balance,
+            overwritten: overwritten(op, &read.address, written.balance),
+            writer,
+        });
+    }
+
+    // Reads of accounts the reader does not write itself, which no later write of its own can expose
+    let blind_reads = |op: &Operation| -> Vec<(String, i64)> {
+        op.reads.iter().filter(|r| write_of(op, &r.address).is_none()).map(|r| (r.address.clone(), r.version)).collect()
+    };
+    for (i, a) in committed.iter().enumerate() {
+        for (x, read_x) in blind_reads(a) {
+            for b in writers.get(x.as_str()).into_iter().flatten() {
+                if std::ptr::eq(*a, *b) || !concurrent(a, b) || write_of(b, &x).is_some_and(|w| w.version == read_x) {
+                    continue;
+                }
+                // Report each pair once, from the operation that comes first in the history
+                let j = committed.iter().position(|op| std::ptr::eq(*op, *b)).expect("writers are committed");
+                if j < i {
+                    continue;
+                }
+                for (y, read_y) in blind_reads(b) {
+                    if y != x && write_of(a, &y).is_some_and(|w| w.version != read_y) {
+                        anomalies.push(Anomaly::WriteSkew {
+                            tx_hashes: [a.tx_hash.clone(), b.tx_hash.clone()],
+                            addresses: [x.clone(), y],
+                        });
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    for op in &committed {
+        for (i, x) in op.reads.iter().enumerate() {
+            for y in &op.reads[i + 1..] {
+                if x.address == y.address {
+                    continue;
+                }
+                for u in writers.get(x.address.as_str()).into_iter().flatten() {
+                    if std::ptr::eq(*u, *op) || !concurrent(u, op) {
+                        continue;
+                    }
+                    let (Some(ux), Some(uy)) = (write_of(u, &x.address), write_of(u, &y.address)) else { continue };
+                    let skewed = if x.version == ux.version && y.version == uy.version - 1 {
+                        Some([x.address.clone(), y.address.clone()])
+                    } else if y.version == uy.version && x.version == ux.version - 1 {
+                        Some([y.address.clone(), x.address.clone()])
+                    } else {
+                        None
+                    };
+                    if let Some(addresses) = skewed {
+                        anomalies.push(Anomaly::ReadSkew { tx_hash: op.tx_hash.clone(), writer: u.tx_hash.clone(), addresses });
+                    }
+                }
+            }
+        }
+    }
+
+    anomalies
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type Accesses<'a> = &'a [(&'a str, i64, i64)];
+
+    fn op(tx_hash: &str, from: &str, to: &str, span: (u64, u64), reads: Accesses, writes: Accesses) -> Operation {
+        let access = |list: Accesses| {
+            list.iter().map(|(a, balance, version)| Access { address: a.to_string(), balance: *balance, version: *version }).collect()
+        };
+        Operation {
+            worker: 0,
+            tx_hash: tx_hash.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+            amount: 3,
+            attempt: 1,
+            invoked_ns: span.0,
+            completed_ns: span.1,
+            reads: access(reads),
+            writes: access(writes),
+            result: Completion::Commit,
+        }
+    }
+
+    fn lost_update(balance: i64, version: i64, tx_hashes: &[&str]) -> Anomaly {
+        Anomaly::LostUpdate {
+            address: "0x0".to_string(),
+            balance,
+            version,
+            tx_hashes: tx_hashes.iter().map(|h| h.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_lost_update() {
+        // Both read 3, the second debit lands on the first one's 0
+        let history = [
+            op("1", "0x0", "0x1", (0, 10), &[("0x0", 3, 1)], &[("0x0", 0, 2), ("0x1", 3, 1)]),
+            op("2", "0x0", "0x2", (1, 20), &[("0x0", 3, 1)], &[("0x0", -3, 3), ("0x2", 3, 1)]),
+        ];
+        assert_eq!(detect(&history), vec![lost_update(3, 1, &["1", "2"])]);
+    }
+
+    #[test]
+    fn test_lost_updates_of_versions_with_the_same_balance_are_told_apart() {
+        // 0x0 is back at 9 after two credits, the second pair of debits loses an update of its own
+        let history = [
+            op("1", "0x0", "0x1", (0, 10), &[("0x0", 9, 1)], &[("0x0", 6, 2), ("0x1", 3, 1)]),
+            op("2", "0x0", "0x2", (1, 12), &[("0x0", 9, 1)], &[("0x0", 3, 3), ("0x2", 3, 1)]),
+            op("3", "0x9", "0x0", (20, 21), &[], &[("0x9", 0, 1), ("0x0", 6, 4)]),
+            op("4", "0x9", "0x0", (22, 23), &[], &[("0x9", -3, 2), ("0x0", 9, 5)]),
+            op("5", "0x0", "0x1", (30, 40), &[("0x0", 9, 5)], &[("0x0", 6, 6), ("0x1", 6, 2)]),
+            op("6", "0x0", "0x2", (31, 42), &[("0x0", 9, 5)], &[("0x0", 3, 7), ("0x2", 6, 2)]),
+        ];
+        assert_eq!(detect(&history), vec![lost_update(9, 1, &["1", "2"]), lost_update(9, 5, &["5", "6"])]);
+    }
+
+    #[test]
+    fn test_non_repeatable_read() {
+        // 3 read 6 before 2 committed, 1's own read of 9 is long gone
+        let history = [
+            op("1", "0x0", "0x1", (0, 10), &[("0x0", 9, 1)], &[("0x0", 6, 2), ("0x1", 3, 1)]),
+            op("2", "0x0", "0x2", (5, 20), &[("0x0", 9, 1)], &[("0x0", 3, 3), ("0x2", 3, 1)]),
+            op("3", "0x0", "0x3", (15, 30), &[("0x0", 6, 2)], &[("0x0", 0, 4), ("0x3", 3, 1)]),
+        ];
+        let anomalies = detect(&history);
+        assert_eq!(anomalies.len(), 2);
+        assert!(anomalies.contains(&lost_update(9, 1, &["1", "2"])));
+        assert!(anomalies.contains(&Anomaly::NonRepeatableRead {
+            tx_hash: "3".to_string(),
+            address: "0x0".to_string(),
+            read: 6,
+            overwritten: 3,
+            writer: Some("2".to_string()),
+        }));
+    }
+
+    #[test]
+    fn test_write_skew() {
+        // Each checks the other account before moving money away from it
+        let history = [
+            op("1", "0x1", "0x2", (0, 10), &[("0x0", 5, 1)], &[("0x1", 2, 2), ("0x2", 8, 2)]),
+            op("2", "0x0", "0x3", (1, 20), &[("0x1", 5, 1)], &[("0x0", 2, 2), ("0x3", 8, 2)]),
+        ];
+        assert_eq!(
+            detect(&history),
+            vec![Anomaly::WriteSkew {
+                tx_hashes: ["1".to_string(), "2".to_string()],
+                addresses: ["0x0".to_string(), "0x1".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_read_skew() {
+        // 2 sees 1's debit of 0x0 but not its credit of 0x1
+        let history = [
+            op("1", "0x0", "0x1", (0, 10), &[("0x0", 10, 1)], &[("0x0", 7, 2), ("0x1", 13, 2)]),
+            op("2", "0x0", "0x1", (5, 6), &[("0x0", 7, 2), ("0x1", 10, 1)], &[]),
+        ];
+        assert_eq!(
+            detect(&history),
+            vec![Anomaly::ReadSkew { tx_hash: "2".to_string(), writer: "1".to_string(), addresses: ["0x0".to_string(), "0x1".to_string()] }]
+        );
+    }
+
+    #[test]
+    fn test_serial_history_is_clean() {
+        let mut history = vec![
+            op("1", "0x0", "0x1", (0, 10), &[("0x0", 6, 1)], &[("0x0", 3, 2), ("0x1", 3, 1)]),
+            op("2", "0x0", "0x1", (11, 20), &[("0x0", 3, 2), ("0x1", 3, 1)], &[("0x0", 0, 3), ("0x1", 6, 2)]),
+        ];
+        // Aborted attempts are ignored, whatever they read
+        let mut aborted = op("3", "0x0", "0x1", (5, 15), &[("0x0", 6, 1)], &[]);
+        aborted.result = Completion::Abort { reason: "Insufficient funds".to_string() };
+        history.push(aborted);
+        assert!(detect(&history).is_empty());
+    }
+}
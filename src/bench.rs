@@ -0,0 +1,169 @@
+use std::{fmt, time::Duration};
+
+use lock_and_transaction::{
+    run::{run_workload, RunSummary},
+    strategy::{TransferStrategy, STRATEGIES},
+    workload::PairGenerator,
+    IsolationLevel,
+};
+use sqlx::PgPool;
+
+use crate::{verify, Args};
+
+/// How one strategy did on the benchmark workload
+#[derive(Debug, serde::Serialize)]
+pub struct BenchResult {
+    pub strategy: &'static str,
+    /// The server's default when not given
+    pub isolation: Option<IsolationLevel>,
+    /// Applied transfers per second of wall clock time
+    pub commits_per_sec: f64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+    pub elapsed_ms: f64,
+    pub consistent: bool,
+    pub expected_consistent: bool,
+    pub summary: RunSummary,
+}
+
+impl BenchResult {
+    /// Transfers that ended in an error, after retries
+    fn failed(&self) -> usize {
+        self.summary.total - self.summary.applied - self.summary.duplicates - self.rejected()
+    }
+
+    fn rejected(&self) -> usize {
+        self.summary.insufficient_funds + self.summary.account_not_found
+    }
+
+    /// Attempts made beyond the first one
+    fn retries(&self) -> u64 {
+        self.summary.retry_attempts - self.summary.retried as u64
+    }
+}
+
+/// Runs the workload once per strategy, or once per strategy and isolation level with
+/// `--isolation-matrix`, each time on freshly seeded accounts. Prints a table and optionally writes
+/// the results as JSON. Returns false if a strategy that is expected to stay consistent did not
+pub async fn run(args: &Args, pool: &PgPool, pairs: &PairGenerator) -> bool {
+    let runs = runs(args);
+    let options = args.run_options();
+    let mut results = Vec::with_capacity(runs.len());
+    for (strategy, isolation) in runs {
+        tracing::info!("Benchmarking {} at {:?}", strategy.name(), isolation);
+        let run = run_workload(&options, pool, pairs, strategy, isolation, None).await.expect("Failed to run the workload");
+        let report = verify(pool, args).await.expect("Failed to verify ledger consistency");
+
+        let mut latencies = run.latencies;
+        latencies.sort_unstable();
+        let elapsed = run.elapsed.as_secs_f64();
+        results.push(BenchResult {
+            strategy: strategy.name(),
+            isolation,
+            commits_per_sec: if elapsed > 0.0 { run.summary.applied as f64 / elapsed } else { 0.0 },
+            p50_ms: millis(percentile(&latencies, 50.0)),
+            p95_ms: millis(percentile(&latencies, 95.0)),
+            p99_ms: millis(percentile(&latencies, 99.0)),
+            max_ms: millis(latencies.last().copied().unwrap_or_default()),
+            elapsed_ms: millis(run.elapsed),
+            consistent: report.is_consistent(),
+            expected_consistent: strategy.expected_consistent(),
+            summary: run.summary,
+        });
+    }
+
+    println!("{}", Table(&results));
+
+    if let Some(path) = &args.json {
+        let json = serde_json::to_string_pretty(&results).expect("Failed to serialize benchmark results");
+        std::fs::write(path, json).expect("Failed to write benchmark results");
+    }
+
+    results.iter().all(|r| r.consistent || !r.expected_consistent)
+}
+
+/// Every strategy with the isolation level it runs at. A strategy that pins its own level is only
+/// run at that level, so its rows are not labelled with levels it ignores
+fn runs(args: &Args) -> Vec<(&'static dyn TransferStrategy, Option<IsolationLevel>)> {
+    if args.isolation_matrix {
+        IsolationLevel::ALL
+            .iter()
+            .flat_map(|&level| STRATEGIES.iter().map(move |&s| (s, Some(level))))
+            .filter(|(s, level)| s.isolation().is_none_or(|pinned| Some(pinned) == *level))
+            .collect()
+    } else {
+        STRATEGIES.iter().map(|&s| (s, args.isolation(s.name()))).collect()
+    }
+}
+
+/// Nearest-rank percentile of already sorted `latencies`
+fn percentile(latencies: &[Duration], p: f64) -> Duration {
+    if latencies.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p / 100.0 * latencies.len() as f64).ceil() as usize;
+    latencies[rank.clamp(1, latencies.len()) - 1]
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+struct Table<'a>(&'a [BenchResult]);
+
+impl fmt::Display for Table<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<14} {:<16} {:>10} {:>9} {:>9} {:>9} {:>9} {:>8} {:>8} {:>7} {:>8}  consistent",
+            "strategy", "isolation", "commits/s", "p50 ms", "p95 ms", "p99 ms", "max ms", "applied", "rejected", "failed", "retries"
+        )?;
+        for r in self.0 {
+            let consistent = match (r.consistent, r.expected_consistent) {
+                (true, _) => "yes",
+                (false, false) => "no (expected)",
+                (false, true) => "NO",
+            };
+            writeln!(
+                f,
+                "{:<14} {:<16} {:>10.1} {:>9.1} {:>9.1} {:>9.1} {:>9.1} {:>8} {:>8} {:>7} {:>8}  {}",
+                r.strategy, r.isolation.map_or("default".to_string(), |level| level.to_string()), r.commits_per_sec, r.p50_ms, r.p95_ms, r.p99_ms, r.max_ms,
+                r.summary.applied, r.rejected(), r.failed(), r.retries(), consistent
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_percentile_nearest_rank() {
+        let latencies: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&latencies, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&latencies, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&latencies[..1], 95.0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_matrix_runs_pinned_strategies_at_their_level_only() {
+        let args = <Args as clap::Parser>::try_parse_from(["lock-and-transaction", "--bench", "--isolation-matrix", "--isolation", "read-committed"]).unwrap();
+        let matrix = runs(&args);
+        assert_eq!(matrix.len(), IsolationLevel::ALL.len() * (STRATEGIES.len() - 1) + 1);
+        for (strategy, level) in matrix {
+            if let Some(pinned) = strategy.isolation() {
+                assert_eq!(level, Some(pinned), "{}", strategy.name());
+            }
+        }
+
+        let args = <Args as clap::Parser>::try_parse_from(["lock-and-transaction", "--bench", "--isolation", "read-committed"]).unwrap();
+        let serializable = runs(&args).into_iter().find(|(s, _)| s.name() == "serializable").unwrap();
+        assert_eq!(serializable.1, Some(IsolationLevel::Serializable));
+    }
+}
@@ -0,0 +1,283 @@
+use std::{fmt, future::Future, pin::Pin, str::FromStr, sync::Arc, time::Duration};
+
+use sqlx::PgConnection;
+use tokio::sync::{Mutex, Notify};
+
+use crate::Error;
+
+/// Named points in a transfer, in the order a transfer passes them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Checkpoint {
+    /// The sender's balance was read, strategies that fold the check into the debit skip this one
+    AfterReadBalance,
+    /// The sender was debited
+    AfterDebit,
+    /// The recipient was credited
+    AfterCredit,
+    /// Everything is written, the transaction is about to commit
+    BeforeCommit,
+}
+
+impl Checkpoint {
+    pub const ALL: [Checkpoint; 4] =
+        [Checkpoint::AfterReadBalance, Checkpoint::AfterDebit, Checkpoint::AfterCredit, Checkpoint::BeforeCommit];
+
+    /// Name of the checkpoint in hooks, e.g. `after-read-balance`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Checkpoint::AfterReadBalance => "after-read-balance",
+            Checkpoint::AfterDebit => "after-debit",
+            Checkpoint::AfterCredit => "after-credit",
+            Checkpoint::BeforeCommit => "before-commit",
+        }
+    }
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+pub type CheckpointFuture<'a> = Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>>;
+
+/// Something the transfer of a task reports its progress to
+pub trait Observer: Send + Sync {
+    /// A transaction of the transfer began, served by the backend `pid`
+    fn began(&self, pid: i32);
+    /// The transfer reached `checkpoint` and carries on once the returned future resolves.
+    /// An error ends the attempt with that error
+    fn reached(&self, checkpoint: Checkpoint) -> CheckpointFuture<'_>;
+}
+
+tokio::task_local! {
+    static OBSERVER: Arc<dyn Observer>;
+}
+
+/// Runs `fut`, one transfer, reporting its progress to `observer` when one is given
+pub async fn scope<F: Future>(observer: Option<Arc<dyn Observer>>, fut: F) -> F::Output {
+    match observer {
+        Some(observer) => OBSERVER.scope(observer, fut).await,
+        None => fut.await,
+    }
+}
+
+/// Tells the observer, if any, which backend serves the transaction on `conn`
+pub async fn began(conn: &mut PgConnection) -> Result<(), Error> {
+    let Ok(observer) = OBSERVER.try_with(Arc::clone) else {
+        return Ok(());
+    };
+    let pid = sqlx::query_scalar!(r#"SELECT pg_backend_pid() AS "pid!""#).fetch_one(conn).await?;
+    observer.began(pid);
+    Ok(())
+}
+
+/// Reports `checkpoint` to the observer, if any, and waits until it lets the transfer go on
+pub async fn reached(checkpoint: Checkpoint) -> Result<(), Error> {
+    let Ok(observer) = OBSERVER.try_with(Arc::clone) else {
+        return Ok(());
+    };
+    observer.reached(checkpoint).await
+}
+
+/// What a hook does to a transfer that reaches its checkpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Holds the transfer, and whatever its transaction has locked, for a while
+    Sleep(Duration),
+    /// Holds the transfer until `parties` transfers wait at the checkpoint, or `timeout` passed.
+    /// The timeout keeps transfers that block each other from waiting forever
+    Barrier { parties: usize, timeout: Duration },
+    /// Panics, the transaction is dropped without commit or rollback
+    Panic,
+    /// Ends the attempt with an error, which rolls the transaction back
+    Rollback,
+}
+
+/// An action attached to a checkpoint, written `after-read-balance=sleep:50`. Actions are
+/// `sleep:MS`, `barrier:N` or `barrier:N:MS` (1000ms by default), `panic` and `rollback`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hook {
+    pub checkpoint: Checkpoint,
+    pub action: Action,
+}
+
+impl FromStr for Hook {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (checkpoint, action) = value.split_once('=').ok_or("expected CHECKPOINT=ACTION")?;
+        let checkpoint = Checkpoint::ALL
+            .into_iter()
+            .find(|c| c.name().eq_ignore_ascii_case(checkpoint))
+            .ok_or_else(|| format!("unknown checkpoint {}", checkpoint))?;
+        let millis = |arg: &str| arg.parse().map(Duration::from_millis).map_err(|e| format!("invalid milliseconds {}: {}", arg, e));
+
+        let mut args = action.split(':');
+        let action = match (args.next(), args.next(), args.next()) {
+            (Some("sleep"), Some(ms), None) => Action::Sleep(millis(ms)?),
+            (Some("barrier"), Some(parties), timeout) => Action::Barrier {
+                parties: parties.parse().map_err(|e| format!("invalid number of parties {}: {}", parties, e))?,
+                timeout: timeout.map_or(Ok(Duration::from_secs(1)), millis)?,
+            },
+            (Some("panic"), None, None) => Action::Panic,
+            (Some("rollback"), None, None) => Action::Rollback,
+            _ => return Err(format!("unknown action {}", action)),
+        };
+        if args.next().is_some() {
+            return Err(format!("unknown action {}", value));
+        }
+        Ok(Hook { checkpoint, action })
+    }
+}
+
+/// Transfers meet here in groups of `parties`
+struct Barrier {
+    parties: usize,
+    timeout: Duration,
+    /// Transfers waiting for the current group to fill up, and the number of groups released so far
+    state: Mutex<(usize, u64)>,
+    released: Notify,
+}
+
+impl Barrier {
+    async fn wait(&self) {
+        let mut state = self.state.lock().await;
+        state.0 += 1;
+        if state.0 >= self.parties {
+            *state = (0, state.1 + 1);
+            self.released.notify_waiters();
+            return;
+        }
+        let generation = state.1;
+        let released = self.released.notified();
+        tokio::pin!(released);
+        released.as_mut().enable();
+        drop(state);
+
+        if tokio::time::timeout(self.timeout, released).await.is_err() {
+            let mut state = self.state.lock().await;
+            if state.1 == generation {
+                state.0 -= 1;
+            }
+        }
+    }
+}
+
+/// Runs the actions of `hooks` in every transfer it observes
+pub struct Hooks {
+    hooks: Vec<(Hook, Option<Barrier>)>,
+}
+
+impl Hooks {
+    /// Every barrier starts out empty, and is shared by the transfers observed by these hooks
+    pub fn new(hooks: &[Hook]) -> Self {
+        let hooks = hooks
+            .iter()
+            .map(|&hook| {
+                let barrier = match hook.action {
+                    Action::Barrier { parties, timeout } => {
+                        Some(Barrier { parties, timeout, state: Mutex::new((0, 0)), released: Notify::new() })
+                    }
+                    _ => None,
+                };
+                (hook, barrier)
+            })
+            .collect();
+        Hooks { hooks }
+    }
+}
+
+impl Observer for Hooks {
+    fn began(&self, _pid: i32) {}
+
+    fn reached(&self, checkpoint: Checkpoint) -> CheckpointFuture<'_> {
+        Box::pin(async move {
+            for (hook, barrier) in self.hooks.iter().filter(|(hook, _)| hook.checkpoint == checkpoint) {
+                match hook.action {
+                    Action::Sleep(duration) => tokio::time::sleep(duration).await,
+                    Action::Barrier { .. } => barrier.as_ref().expect("barriers are set up in new").wait().await,
+                    Action::Panic => panic!("Panic injected at {}", checkpoint),
+                    Action::Rollback => return Err(Error::RolledBack(checkpoint.to_string())),
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        fixtures::{accounts, balance, ledger, recorded},
+        strategy::strategy,
+        Ledger, TransferOutcome,
+    };
+    use sqlx::PgPool;
+
+    #[test]
+    fn test_parse_hook() {
+        let hook = |value: &str| value.parse::<Hook>();
+        assert_eq!(
+            hook("after-read-balance=sleep:50"),
+            Ok(Hook { checkpoint: Checkpoint::AfterReadBalance, action: Action::Sleep(Duration::from_millis(50)) })
+        );
+        assert_eq!(
+            hook("after-debit=barrier:2"),
+            Ok(Hook { checkpoint: Checkpoint::AfterDebit, action: Action::Barrier { parties: 2, timeout: Duration::from_secs(1) } })
+        );
+        assert_eq!(
+            hook("after-credit=barrier:3:200"),
+            Ok(Hook { checkpoint: Checkpoint::AfterCredit, action: Action::Barrier { parties: 3, timeout: Duration::from_millis(200) } })
+        );
+        assert_eq!(hook("before-commit=panic"), Ok(Hook { checkpoint: Checkpoint::BeforeCommit, action: Action::Panic }));
+        assert_eq!(hook("before-commit=rollback"), Ok(Hook { checkpoint: Checkpoint::BeforeCommit, action: Action::Rollback }));
+        assert!(hook("before-commit").is_err());
+        assert!(hook("after-lunch=panic").is_err());
+        assert!(hook("after-debit=sleep").is_err());
+        assert!(hook("after-debit=sleep:50:1").is_err());
+        assert!(hook("after-debit=rollback:1").is_err());
+    }
+
+    /// Runs a bad transfer of 3 from 0x0 to `to` with `hooks`
+    fn transfer(ledger: &Ledger, hooks: &Arc<Hooks>, tx_hash: &str, to: &str) -> tokio::task::JoinHandle<Result<TransferOutcome, Error>> {
+        let bad = strategy("bad").unwrap();
+        let (ledger, hooks) = (ledger.clone(), hooks.clone() as Arc<dyn Observer>);
+        let (tx_hash, to) = (tx_hash.to_string(), to.to_string());
+        tokio::spawn(async move { scope(Some(hooks), bad.transfer(&ledger, &tx_hash, "0x0", &to, 3)).await })
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_barrier_makes_bad_transfers_race(pool: PgPool) {
+        accounts(&pool, &[("0x0", 3), ("0x1", 0), ("0x2", 0)]).await;
+        let hooks = Arc::new(Hooks::new(&["after-read-balance=barrier:2:5000".parse().unwrap()]));
+        let ledger = ledger(&pool);
+
+        // Neither debits before both read the balance of 3
+        let t1 = transfer(&ledger, &hooks, "1", "0x1");
+        let t2 = transfer(&ledger, &hooks, "2", "0x2");
+        assert!(matches!(t1.await.unwrap(), Ok(TransferOutcome::Applied { .. })));
+        assert!(matches!(t2.await.unwrap(), Ok(TransferOutcome::Applied { .. })));
+        assert_eq!(balance(&pool, "0x0").await, -3);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_rollback_and_panic_leave_balances_alone(pool: PgPool) {
+        accounts(&pool, &[("0x0", 3), ("0x1", 0), ("0x2", 0)]).await;
+        let ledger = ledger(&pool);
+
+        let rollback = Arc::new(Hooks::new(&["before-commit=rollback".parse().unwrap()]));
+        let res = transfer(&ledger, &rollback, "1", "0x1").await.unwrap();
+        assert!(matches!(res, Err(Error::RolledBack(ref at)) if at == "before-commit"), "{:?}", res);
+        assert!(!res.unwrap_err().is_retryable());
+
+        let panic = Arc::new(Hooks::new(&["after-credit=panic".parse().unwrap()]));
+        assert!(transfer(&ledger, &panic, "2", "0x2").await.unwrap_err().is_panic());
+
+        assert_eq!(balance(&pool, "0x0").await, 3);
+        assert_eq!(balance(&pool, "0x1").await, 0);
+        assert_eq!(balance(&pool, "0x2").await, 0);
+        assert_eq!(recorded(&pool).await, 0);
+    }
+}
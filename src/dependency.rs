@@ -0,0 +1,413 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt,
+};
+
+use crate::{
+    history::{Completion, Operation},
+    IsolationLevel,
+};
+
+/// Why one committed transaction has to come before another in any equivalent serial order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Dependency {
+    /// The second one overwrote the version the first one wrote
+    Ww,
+    /// The second one read the version the first one wrote
+    Wr,
+    /// The second one overwrote the version the first one read, an anti-dependency
+    Rw,
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dependency::Ww => write!(f, "ww"),
+            Dependency::Wr => write!(f, "wr"),
+            Dependency::Rw => write!(f, "rw"),
+        }
+    }
+}
+
+/// Adya's phenomena that show up as cycles in the dependency graph, roughly from the weakest
+/// isolation level that proscribes them to the strongest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phenomenon {
+    /// A cycle of ww edges only
+    G0,
+    /// A cycle of ww and wr edges
+    G1c,
+    /// A cycle with exactly one rw edge, e.g. a lost update
+    GSingle,
+    /// A cycle with more than one rw edge, e.g. write skew
+    G2Item,
+}
+
+impl Phenomenon {
+    /// The weakest isolation level that does not allow the phenomenon in Postgres, where
+    /// REPEATABLE READ is snapshot isolation and allows write skew
+    pub fn violated_level(&self) -> IsolationLevel {
+        match self {
+            Phenomenon::G0 | Phenomenon::G1c => IsolationLevel::ReadCommitted,
+            Phenomenon::GSingle => IsolationLevel::RepeatableRead,
+            Phenomenon::G2Item => IsolationLevel::Serializable,
+        }
+    }
+}
+
+impl fmt::Display for Phenomenon {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phenomenon::G0 => write!(f, "G0"),
+            Phenomenon::G1c => write!(f, "G1c"),
+            Phenomenon::GSingle => write!(f, "G-single"),
+            Phenomenon::G2Item => write!(f, "G2-item"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Edge {
+    from: usize,
+    to: usize,
+    kind: Dependency,
+    address: String,
+}
+
+/// A cycle in the dependency graph, each step names the transaction and the edge leaving it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cycle {
+    pub phenomenon: Phenomenon,
+    pub steps: Vec<(String, Dependency, String)>,
+}
+
+impl fmt::Display for Cycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (not allowed under {}): ", self.phenomenon, self.phenomenon.violated_level())?;
+        for (tx_hash, kind, address) in &self.steps {
+            write!(f, "{} -{}({})-> ", tx_hash, kind, address)?;
+        }
+        write!(f, "{}", self.steps[0].0)
+    }
+}
+
+/// Dependencies between the committed transactions of a history. Versions come from the
+/// accounts' `version` column, so the order of writes to an account is exact
+pub struct DependencyGraph<'h> {
+    ops: Vec<&'h Operation>,
+    edges: Vec<Edge>,
+    /// Indices into `edges` of the edges leaving each transaction
+    out: Vec<Vec<usize>>,
+}
+
+impl<'h> DependencyGraph<'h> {
+    /// Builds the graph of the committed operations of `history`. Operations whose outcome is
+    /// unknown are left out, so their dependencies are missed
+    pub fn build(history: &'h [Operation]) -> Self {
+        let ops: Vec<&Operation> = history.iter().filter(|op| op.result == Completion::Commit).collect();
+
+        let mut versions: HashMap<&str, BTreeMap<i64, usize>> = HashMap::new();
+        for (i, op) in ops.iter().enumerate() {
+            for w in &op.writes {
+                versions.entry(&w.address).or_default().entry(w.version).or_insert(i);
+            }
+        }
+
+        let mut edges = Vec::new();
+        let mut seen = HashSet::new();
+        let mut add = |from: usize, to: usize, kind: Dependency, address: &str| {
+            if from != to && seen.insert((from, to, kind)) {
+                edges.push(Edge { from, to, kind, address: address.to_string() });
+            }
+        };
+        for (address, writers) in &versions {
+            let writers: Vec<usize> = writers.values().copied().collect();
+            for pair in writers.windows(2) {
+                add(pair[0], pair[1], Dependency::Ww, address);
+            }
+        }
+        for (i, op) in ops.iter().enumerate() {
+            for r in &op.reads {
+                let Some(writers) = versions.get(r.address.as_str()) else { continue };
+                if let Some(&writer) = writers.get(&r.version) {
+                    add(writer, i, Dependency::Wr, &r.address);
+                }
+                if let Some((_, &next)) = writers.range(r.version + 1..).next() {
+                    add(i, next, Dependency::Rw, &r.address);
+                }
+            }
+        }
+
+        let mut out = vec![Vec::new(); ops.len()];
+        for (e, edge) in edges.iter().enumerate() {
+            out[edge.from].push(e);
+        }
+        DependencyGraph { ops, edges, out }
+    }
+
+    fn count(&self, kind: Dependency) -> usize {
+        self.edges.iter().filter(|e| e.kind == kind).count()
+    }
+
+    /// Strongly connected components with more than one transaction, using only edges `allowed`
+    fn components(&self, allowed: impl Fn(Dependency) -> bool) -> Vec<Vec<usize>> {
+        let n = self.ops.len();
+        let mut into = vec![Vec::new(); n];
+        for edge in self.edges.iter().filter(|e| allowed(e.kind)) {
+            into[edge.to].push(edge.from);
+        }
+
+        // Kosaraju, iteratively: finish order on the graph, then collect on the reversed graph
+        let mut order = Vec::with_capacity(n);
+        let mut visited = vec![false; n];
+        for start in 0..n {
+            if visited[start] {
+                continue;
+            }
+            visited[start] = true;
+            let mut stack = vec![(start, 0)];
+            while let Some((node, next)) = stack.pop() {
+                let succ = self.out[node][next..].iter().map(|&e| &self.edges[e]).position(|e| allowed(e.kind) && !visited[e.to]);
+                match succ {
+                    Some(offset) => {
+                        let to = self.edges[self.out[node][next + offset]].to;
+                        stack.push((node, next + offset + 1));
+                        visited[to] = true;
+                        stack.push((to, 0));
+                    }
+                    None => order.push(node),
+                }
+            }
+        }
+
+        let mut component = vec![usize::MAX; n];
+        let mut components = Vec::new();
+        for &start in order.iter().rev() {
+            if component[start] != usize::MAX {
+                continue;
+            }
+            let id = components.len();
+            let mut members = vec![start];
+            component[start] = id;
+            let mut stack = vec![start];
+            while let Some(node) = stack.pop() {
+                for &from in &into[node] {
+                    if component[from] == usize::MAX {
+                        component[from] = id;
+                        members.push(from);
+                        stack.push(from);
+                    }
+                }
+            }
+            components.push(members);
+        }
+        components.retain(|c| c.len() > 1);
+        components
+    }
+
+    /// Shortest path from `from` to `to` through `members` over edges `allowed`, as edge indices
+    fn path(&self, from: usize, to: usize, members: &HashSet<usize>, allowed: &impl Fn(Dependency) -> bool) -> Option<Vec<usize>> {
+        let mut parent: HashMap<usize, usize> = HashMap::new();
+        let mut queue = VecDeque::from([from]);
+        while let Some(node) = queue.pop_front() {
+            if node == to {
+                let mut path = Vec::new();
+                let mut at = to;
+                while at != from {
+                    let e = parent[&at];
+                    path.push(e);
+                    at = self.edges[e].from;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for &e in &self.out[node] {
+                let edge = &self.edges[e];
+                if allowed(edge.kind) && members.contains(&edge.to) && edge.to != from && !parent.contains_key(&edge.to) {
+                    parent.insert(edge.to, e);
+                    queue.push_back(edge.to);
+                }
+            }
+        }
+        None
+    }
+
+    /// A cycle through `edge` within `members`, returning along edges `allowed`
+    fn cycle_through(&self, edge: usize, members: &HashSet<usize>, allowed: impl Fn(Dependency) -> bool, phenomenon: Phenomenon) -> Option<Cycle> {
+        let Edge { from, to, .. } = self.edges[edge];
+        let back = self.path(to, from, members, &allowed)?;
+        let steps = std::iter::once(edge)
+            .chain(back)
+            .map(|e| {
+                let edge = &self.edges[e];
+                (self.ops[edge.from].tx_hash.clone(), edge.kind, edge.address.clone())
+            })
+            .collect();
+        Some(Cycle { phenomenon, steps })
+    }
+
+    fn internal_edges<'a>(&'a self, members: &'a HashSet<usize>, kind: Dependency) -> impl Iterator<Item = usize> + 'a {
+        (0..self.edges.len()).filter(move |&e| {
+            let edge = &self.edges[e];
+            edge.kind == kind && members.contains(&edge.from) && members.contains(&edge.to)
+        })
+    }
+
+    /// One example cycle per phenomenon per strongly connected component. An empty result means
+    /// the history is serializable
+    pub fn cycles(&self) -> Vec<Cycle> {
+        let ww = |k| k == Dependency::Ww;
+        let ww_wr = |k| k != Dependency::Rw;
+        let any = |_| true;
+        let mut cycles = Vec::new();
+
+        for members in self.components(ww) {
+            let members: HashSet<usize> = members.into_iter().collect();
+            let edge = self.internal_edges(&members, Dependency::Ww).next().expect("a component has edges");
+            cycles.extend(self.cycle_through(edge, &members, ww, Phenomenon::G0));
+        }
+
+        for members in self.components(ww_wr) {
+            let members: HashSet<usize> = members.into_iter().collect();
+            let edge = self.internal_edges(&members, Dependency::Wr).next();
+            if let Some(edge) = edge {
+                cycles.extend(self.cycle_through(edge, &members, ww_wr, Phenomenon::G1c));
+            }
+        }
+
+        for members in self.components(any) {
+            let members: HashSet<usize> = members.into_iter().collect();
+            let mut anti = self.internal_edges(&members, Dependency::Rw).peekable();
+            let Some(&first) = anti.peek() else { continue };
+            match anti.find_map(|e| self.cycle_through(e, &members, ww_wr, Phenomenon::GSingle)) {
+                Some(cycle) => cycles.push(cycle),
+                None => cycles.extend(self.cycle_through(first, &members, any, Phenomenon::G2Item)),
+            }
+        }
+
+        cycles
+    }
+}
+
+impl fmt::Display for DependencyGraph<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Dependency graph: {} committed transactions, {} ww, {} wr, {} rw edges",
+            self.ops.len(),
+            self.count(Dependency::Ww),
+            self.count(Dependency::Wr),
+            self.count(Dependency::Rw)
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        checkpoint::Checkpoint,
+        fixtures::accounts,
+        history::{self, Access, Recorder},
+        interleave::{Interleaving, Step},
+        strategy::strategy,
+    };
+    use sqlx::PgPool;
+
+    fn op(tx_hash: &str, reads: &[(&str, i64)], writes: &[(&str, i64)]) -> Operation {
+        let access = |list: &[(&str, i64)]| list.iter().map(|(a, v)| Access { address: a.to_string(), balance: 0, version: *v }).collect();
+        Operation {
+            worker: 0,
+            tx_hash: tx_hash.to_string(),
+            from: "0x0".to_string(),
+            to: "0x1".to_string(),
+            amount: 3,
+            attempt: 1,
+            invoked_ns: 0,
+            completed_ns: 0,
+            reads: access(reads),
+            writes: access(writes),
+            result: Completion::Commit,
+        }
+    }
+
+    fn phenomena(history: &[Operation]) -> Vec<Phenomenon> {
+        DependencyGraph::build(history).cycles().iter().map(|c| c.phenomenon).collect()
+    }
+
+    #[test]
+    fn test_serial_history_has_no_cycles() {
+        let history = [
+            op("1", &[("0x0", 0)], &[("0x0", 1), ("0x1", 1)]),
+            op("2", &[("0x0", 1)], &[("0x0", 2), ("0x2", 1)]),
+            op("3", &[], &[("0x0", 3), ("0x1", 2)]),
+        ];
+        assert!(phenomena(&history).is_empty());
+    }
+
+    #[test]
+    fn test_write_cycle_is_g0() {
+        let history = [op("1", &[], &[("0x0", 1), ("0x1", 2)]), op("2", &[], &[("0x0", 2), ("0x1", 1)])];
+        assert_eq!(phenomena(&history), vec![Phenomenon::G0]);
+    }
+
+    #[test]
+    fn test_circular_information_flow_is_g1c() {
+        // Each read what the other one wrote
+        let history = [op("1", &[("0x1", 1)], &[("0x0", 1)]), op("2", &[("0x0", 1)], &[("0x1", 1)])];
+        assert_eq!(phenomena(&history), vec![Phenomenon::G1c]);
+    }
+
+    #[test]
+    fn test_lost_update_is_g_single() {
+        // Both read version 0 of 0x0, 2 overwrote 1's write without having seen it
+        let history = [
+            op("1", &[("0x0", 0)], &[("0x0", 1), ("0x1", 1)]),
+            op("2", &[("0x0", 0)], &[("0x0", 2), ("0x2", 1)]),
+        ];
+        let cycles = DependencyGraph::build(&history).cycles();
+        assert_eq!(cycles.len(), 1);
+        assert_eq!(cycles[0].phenomenon, Phenomenon::GSingle);
+        assert_eq!(
+            cycles[0].steps,
+            vec![("2".to_string(), Dependency::Rw, "0x0".to_string()), ("1".to_string(), Dependency::Ww, "0x0".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_write_skew_is_g2_item() {
+        let history = [op("1", &[("0x0", 0)], &[("0x1", 1)]), op("2", &[("0x1", 0)], &[("0x0", 1)])];
+        assert_eq!(phenomena(&history), vec![Phenomenon::G2Item]);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_only_bad_transfers_form_cycles(pool: PgPool) {
+        for (name, lost_update) in [("good", false), ("bad", true)] {
+            accounts(&pool, &[("0x0", 3), ("0x1", 0), ("0x2", 0)]).await;
+            let path = std::env::temp_dir().join(format!("dependency-{}-{}.jsonl", std::process::id(), name));
+            let recorder = Recorder::create(&path).unwrap();
+            let mut run = Interleaving::new(&pool);
+            run.record(&recorder);
+            let first = run.spawn(strategy(name).unwrap(), "1", "0x0", "0x1", 3);
+            let second = run.spawn(strategy(name).unwrap(), "2", "0x0", "0x2", 3);
+
+            // bad has both read the balance before either debits. good folds the read into its
+            // debit, so the second waits for the first one's lock on the sender
+            run.run_until(first, Checkpoint::AfterReadBalance).await;
+            let step = run.run_until(second, Checkpoint::AfterReadBalance).await;
+            assert_eq!(step, if lost_update { Step::Paused(Checkpoint::AfterReadBalance) } else { Step::Blocked }, "{}", name);
+            run.finish(first).await.unwrap();
+            run.finish(second).await.unwrap();
+            recorder.flush().unwrap();
+            let history = history::load(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            let found = phenomena(&history);
+            if lost_update {
+                assert_eq!(found, vec![Phenomenon::GSingle], "{}", name);
+            } else {
+                assert!(found.is_empty(), "{}: {:?}", name, found);
+            }
+        }
+    }
+}
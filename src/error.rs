@@ -0,0 +1,174 @@
+//! Errors of the ledger. Database errors are classified by their SQLSTATE into the failures a
+//! transfer can run into under concurrency, e.g. 40001 serialization failures, 40P01 deadlocks
+//! or a lost connection, and everything else is kept as `Error::Sqlx`. `Error::is_retryable`
+//! tells which of them a fresh attempt of the same transfer may get past
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// SQLSTATE 40001, a SERIALIZABLE or REPEATABLE READ transaction lost a conflict
+    #[error("Serialization failure: {0}")]
+    SerializationFailure(#[source] sqlx::Error),
+    /// SQLSTATE 40P01, Postgres broke a lock cycle by aborting this transaction
+    #[error("Deadlock detected: {0}")]
+    Deadlock(#[source] sqlx::Error),
+    /// SQLSTATE 55P03, a NOWAIT lock or `lock_timeout` gave up
+    #[error("Lock not available: {0}")]
+    LockNotAvailable(#[source] sqlx::Error),
+    /// SQLSTATE 23505
+    #[error("Unique violation: {0}")]
+    UniqueViolation(#[source] sqlx::Error),
+    /// SQLSTATE 23514, includes exceptions raised by the postings balance trigger
+    #[error("Check violation: {0}")]
+    CheckViolation(#[source] sqlx::Error),
+    /// SQLSTATE 23503
+    #[error("Foreign key violation: {0}")]
+    ForeignKeyViolation(#[source] sqlx::Error),
+    /// The connection broke or the server shut it down, the transaction may or may not have committed
+    #[error("Connection lost: {0}")]
+    ConnectionLost(#[source] sqlx::Error),
+    #[error("SQL error: {0}")]
+    Sqlx(#[source] sqlx::Error),
+    #[error("Version conflict on account({0})")]
+    Conflict(String),
+    /// A hook rolled the transaction back at the named checkpoint
+    #[error("Rolled back at {0}")]
+    RolledBack(String),
+    /// The last error of a transfer that was attempted more than once, `failures` are why the
+    /// attempts before it failed
+    #[error("{source} (after {attempts} attempts)")]
+    Retried { attempts: u32, failures: Vec<Failure>, source: Box<Error> },
+    #[error("unknown error: {0}")]
+    Other(String),
+}
+
+/// Why an attempt failed in a way that a fresh attempt may get past
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Failure {
+    SerializationFailure,
+    Deadlock,
+    LockNotAvailable,
+    ConnectionLost,
+    Conflict,
+}
+
+impl Error {
+    /// Whether running the same transfer again in a fresh transaction may succeed.
+    /// A transfer that already went through its retries is not retried again.
+    /// Retrying after a lost connection is only safe because transfers are idempotent by tx_hash
+    pub fn is_retryable(&self) -> bool {
+        self.failure().is_some()
+    }
+
+    /// The failure this error is when it is retryable
+    pub fn failure(&self) -> Option<Failure> {
+        match self {
+            Error::SerializationFailure(_) => Some(Failure::SerializationFailure),
+            Error::Deadlock(_) => Some(Failure::Deadlock),
+            Error::LockNotAvailable(_) => Some(Failure::LockNotAvailable),
+            Error::ConnectionLost(_) => Some(Failure::ConnectionLost),
+            Error::Conflict(_) => Some(Failure::Conflict),
+            _ => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        if let sqlx::Error::Io(_) = e {
+            return Error::ConnectionLost(e);
+        }
+        let Some(code) = e.as_database_error().and_then(|e| e.code()) else {
+            return Error::Sqlx(e);
+        };
+
+        match code.as_ref() {
+            "40001" => Error::SerializationFailure(e),
+            "40P01" => Error::Deadlock(e),
+            "55P03" => Error::LockNotAvailable(e),
+            "23505" => Error::UniqueViolation(e),
+            "23514" => Error::CheckViolation(e),
+            "23503" => Error::ForeignKeyViolation(e),
+            // Class 08 is connection exception, 57P01-57P03 are the server terminating the
+            // backend, e.g. pg_terminate_backend or a shutdown
+            code if code.starts_with("08") || matches!(code, "57P01" | "57P02" | "57P03") => {
+                Error::ConnectionLost(e)
+            }
+            _ => Error::Sqlx(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{borrow::Cow, error::Error as StdError, fmt};
+
+    use sqlx::error::{DatabaseError, ErrorKind};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct FakeDatabaseError(&'static str);
+
+    impl fmt::Display for FakeDatabaseError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "SQLSTATE {}", self.0)
+        }
+    }
+
+    impl StdError for FakeDatabaseError {}
+
+    impl DatabaseError for FakeDatabaseError {
+        fn message(&self) -> &str {
+            self.0
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.0))
+        }
+
+        fn as_error(&self) -> &(dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn StdError + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    fn classify(code: &'static str) -> Error {
+        sqlx::Error::Database(Box::new(FakeDatabaseError(code))).into()
+    }
+
+    #[test]
+    fn test_classify_sqlstate() {
+        assert!(matches!(classify("40001"), Error::SerializationFailure(_)));
+        assert!(matches!(classify("40P01"), Error::Deadlock(_)));
+        assert!(matches!(classify("55P03"), Error::LockNotAvailable(_)));
+        assert!(matches!(classify("23505"), Error::UniqueViolation(_)));
+        assert!(matches!(classify("23514"), Error::CheckViolation(_)));
+        assert!(matches!(classify("23503"), Error::ForeignKeyViolation(_)));
+        assert!(matches!(classify("08006"), Error::ConnectionLost(_)));
+        assert!(matches!(classify("57P01"), Error::ConnectionLost(_)));
+        assert!(matches!(classify("42P01"), Error::Sqlx(_)));
+        assert!(matches!(Error::from(sqlx::Error::RowNotFound), Error::Sqlx(_)));
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(classify("40001").is_retryable());
+        assert!(classify("40P01").is_retryable());
+        assert!(classify("57P01").is_retryable());
+        assert!(Error::Conflict("0x0".to_string()).is_retryable());
+        assert!(!classify("23505").is_retryable());
+        assert!(!classify("23514").is_retryable());
+        assert!(!Error::Other("nope".to_string()).is_retryable());
+    }
+}
@@ -0,0 +1,145 @@
+//! Faults injected into a running workload, to check that no transfer survives them half applied
+
+use std::{future::Future, time::Duration};
+
+use rand::Rng;
+use sqlx::{ConnectOptions, PgPool};
+use tokio::{sync::oneshot, task::JoinHandle};
+
+use crate::Error;
+
+/// Terminates backends of the workload that are inside a transaction, one every `interval`.
+/// The backends of the workload are those of its pool, told apart by the pool's `application_name`
+pub struct Killer {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<Result<u64, Error>>,
+}
+
+impl Killer {
+    /// Starts killing transactions of connections of `pool`, which needs an `application_name` that
+    /// no other client of the database uses. The killer has a connection of its own, so it neither
+    /// waits for nor takes up a connection of the pool
+    pub async fn spawn(pool: &PgPool, interval: Duration) -> Result<Self, Error> {
+        let options = pool.connect_options();
+        let Some(application_name) = options.get_application_name().map(str::to_string) else {
+            return Err(Error::Other("the pool needs an application_name to kill only its own backends".to_string()));
+        };
+        let mut conn = options.connect().await?;
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let mut killed = 0;
+            loop {
+                tokio::select! {
+                    _ = &mut stopped => return Ok(killed),
+                    _ = tokio::time::sleep(interval) => {}
+                }
+                let terminated = sqlx::query_scalar!(
+                    r#"
+                    SELECT pg_terminate_backend(pid) AS "terminated!"
+                    FROM pg_stat_activity
+                    WHERE datname = current_database()
+                      AND backend_type = 'client backend'
+                      AND application_name = $1
+                      AND pid <> pg_backend_pid()
+                      AND xact_start IS NOT NULL
+                    ORDER BY random()
+                    LIMIT 1
+                    "#,
+                    application_name
+                )
+                .fetch_optional(&mut conn)
+                .await?;
+                if terminated == Some(true) {
+                    killed += 1;
+                }
+            }
+        });
+        Ok(Killer { stop, task })
+    }
+
+    /// Stops the killer and returns how many backends it terminated
+    pub async fn stop(self) -> Result<u64, Error> {
+        let _ = self.stop.send(());
+        self.task.await.expect("killer panicked")
+    }
+}
+
+/// Runs `transfer`, except that with a chance of `rate` it is dropped after a random delay of
+/// up to `within`. Dropping it mid-flight drops its `Transaction` without commit, possibly in
+/// the middle of a statement. A `rate` of 0 or less never drops, 1 or more always does.
+/// Returns `None` when the transfer was dropped
+pub async fn maybe_drop<F: Future>(rate: f64, within: Duration, transfer: F) -> Option<F::Output> {
+    let drop_after = {
+        let mut rng = rand::thread_rng();
+        (rng.gen::<f64>() < rate).then(|| within.mul_f64(rng.gen()))
+    };
+    let Some(drop_after) = drop_after else {
+        return Some(transfer.await);
+    };
+    tokio::time::timeout(drop_after, transfer).await.ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        checkpoint::Checkpoint,
+        fixtures::{accounts, balances},
+        interleave::Interleaving,
+        strategy::strategy,
+        verify::ledger_consistency_verify,
+        Bookkeeping,
+    };
+    use sqlx::postgres::PgPoolOptions;
+
+    /// Good transfer of 3 from 0x0 to 0x1, paused right after its debit
+    async fn debited(pool: &PgPool) -> Interleaving {
+        accounts(pool, &[("0x0", 3), ("0x1", 0)]).await;
+        let mut run = Interleaving::new(pool);
+        let t = run.spawn(strategy("good").unwrap(), "1", "0x0", "0x1", 3);
+        run.run_until(t, Checkpoint::AfterDebit).await;
+        run
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_killed_transfer_leaves_no_partial_debit(pool: PgPool) {
+        let mut run = debited(&pool).await;
+        let pid = run.pid(0).unwrap();
+        let killed = sqlx::query_scalar!(r#"SELECT pg_terminate_backend($1) AS "killed!""#, pid).fetch_one(&pool).await.unwrap();
+        assert!(killed);
+
+        let res = run.finish(0).await;
+        assert!(matches!(res, Err(Error::ConnectionLost(_))), "{:?}", res);
+        assert_eq!(balances(&pool).await, vec![("0x0".to_string(), 3), ("0x1".to_string(), 0)]);
+        assert!(ledger_consistency_verify(&pool, Bookkeeping::SingleEntry).await.unwrap().is_consistent());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_dropped_transfer_leaves_no_partial_debit(pool: PgPool) {
+        let mut run = debited(&pool).await;
+        run.abort(0).await;
+
+        // The debit's row lock is gone with the transaction, so this does not wait
+        let debit = sqlx::query_scalar!("UPDATE accounts SET balance = balance - 1 WHERE address = '0x0' RETURNING balance")
+            .fetch_one(&pool);
+        assert_eq!(tokio::time::timeout(Duration::from_secs(5), debit).await.unwrap().unwrap(), 2);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_killer_spares_other_applications(pool: PgPool) {
+        let with_name = |name: &str| PgPoolOptions::new().connect_with(pool.connect_options().as_ref().clone().application_name(name));
+        let (workload, other) = (with_name("workload").await.unwrap(), with_name("other").await.unwrap());
+        assert!(matches!(Killer::spawn(&pool, Duration::from_millis(5)).await, Err(Error::Other(_))));
+
+        let mut victim = workload.begin().await.unwrap();
+        let mut bystander = other.begin().await.unwrap();
+        sqlx::query("SELECT 1").execute(&mut *victim).await.unwrap();
+        sqlx::query("SELECT 1").execute(&mut *bystander).await.unwrap();
+        let killer = Killer::spawn(&workload, Duration::from_millis(5)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(killer.stop().await.unwrap(), 1);
+        assert!(sqlx::query("SELECT 1").execute(&mut *victim).await.is_err());
+        sqlx::query("SELECT 1").execute(&mut *bystander).await.unwrap();
+    }
+}
@@ -0,0 +1,46 @@
+//! Accounts, ledgers and queries shared by the database tests
+
+use sqlx::PgPool;
+
+use crate::{add_account, clean_up, retry::RetryPolicy, Bookkeeping, Ledger};
+
+/// Starts over with exactly `accounts`, given as address and initial balance
+pub async fn accounts(pool: &PgPool, accounts: &[(&str, u64)]) {
+    clean_up(pool).await.unwrap();
+    for (address, initial) in accounts {
+        add_account(pool, address, *initial).await.unwrap();
+    }
+}
+
+pub async fn balance(pool: &PgPool, address: &str) -> i64 {
+    sqlx::query_scalar!("SELECT balance FROM accounts WHERE address = $1", address)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+/// Every account with its balance, by address
+pub async fn balances(pool: &PgPool) -> Vec<(String, i64)> {
+    sqlx::query!("SELECT address, balance FROM accounts ORDER BY address")
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| (row.address, row.balance))
+        .collect()
+}
+
+/// Number of rows in the `transaction` table
+pub async fn recorded(pool: &PgPool) -> i64 {
+    sqlx::query_scalar!(r#"SELECT count(*) AS "count!" FROM transaction"#).fetch_one(pool).await.unwrap()
+}
+
+/// Single-entry books at the server's default isolation, without retries
+pub fn ledger(pool: &PgPool) -> Ledger {
+    Ledger {
+        pool: pool.clone(),
+        bookkeeping: Bookkeeping::SingleEntry,
+        retry: RetryPolicy { max_attempts: 1, ..Default::default() },
+        isolation: None,
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    cell::RefCell,
+    fs::File,
+    future::Future,
+    io::{self, BufRead, BufReader, BufWriter, Write as _},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::{Error, TransferOutcome};
+
+/// One attempt of a transfer, i.e. one database transaction, as seen by the worker that ran it
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Operation {
+    /// The task that submitted the transfer, a replay runs on a worker of its own
+    pub worker: u64,
+    pub tx_hash: String,
+    pub from: String,
+    pub to: String,
+    pub amount: u64,
+    /// 1 for the first attempt of the transfer
+    pub attempt: u32,
+    /// Nanoseconds since the recorder was created, taken before the transaction begins
+    pub invoked_ns: u64,
+    /// Nanoseconds since the recorder was created, taken after commit or rollback
+    pub completed_ns: u64,
+    /// Balances the transaction read, in the order it read them
+    pub reads: Vec<Access>,
+    /// Balances the transaction wrote, in the order it wrote them
+    pub writes: Vec<Access>,
+    pub result: Completion,
+}
+
+/// A balance read or written by a transaction
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Access {
+    pub address: String,
+    pub balance: i64,
+    /// The account's `version`, which every committed balance change moves on by one
+    pub version: i64,
+}
+
+/// How the transaction of an operation ended
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Completion {
+    /// The transaction committed, its writes are in the database
+    Commit,
+    /// The transaction rolled back, nothing it wrote is in the database
+    Abort { reason: String },
+    /// The connection was lost, the transaction may or may not have committed
+    Unknown { reason: String },
+}
+
+impl Completion {
+    fn of(res: &Result<TransferOutcome, Error>) -> Self {
+        match res {
+            Ok(TransferOutcome::Applied { .. }) => Completion::Commit,
+            Ok(TransferOutcome::Duplicate { .. }) => Completion::Abort { reason: "duplicate".to_string() },
+            Ok(TransferOutcome::Rejected { reason }) => Completion::Abort { reason: reason.to_string() },
+            Ok(TransferOutcome::Retried { outcome, .. }) => Completion::of(&Ok(*outcome.clone())),
+            Err(e @ Error::ConnectionLost(_)) => Completion::Unknown { reason: e.to_string() },
+            Err(e) => Completion::Abort { reason: e.to_string() },
+        }
+    }
+}
+
+/// Appends every operation of a run to a JSON Lines file
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    started: Instant,
+    out: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Recorder { started: Instant::now(), out: Arc::new(Mutex::new(BufWriter::new(File::create(path)?))) })
+    }
+
+    fn now_ns(&self) -> u64 {
+        self.started.elapsed().as_nanos() as u64
+    }
+
+    fn record(&self, op: &Operation) -> io::Result<()> {
+        let mut out = self.out.lock().expect("history writer poisoned");
+        serde_json::to_writer(&mut *out, op)?;
+        out.write_all(b"\n")
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.out.lock().expect("history writer poisoned").flush()
+    }
+}
+
+/// Reads back a history written by a `Recorder`
+pub fn load(path: &Path) -> io::Result<Vec<Operation>> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// The transfer a task is running, and the attempt of it that is in flight
+struct Context {
+    recorder: Recorder,
+    operation: RefCell<Operation>,
+}
+
+tokio::task_local! {
+    static CONTEXT: Context;
+}
+
+/// Runs `fut`, one transfer, recording every attempt it makes when `recorder` is given
+pub async fn scope<F: Future>(recorder: Option<&Recorder>, worker: u64, tx_hash: &str, from: &str, to: &str, amount: u64, fut: F) -> F::Output {
+    let Some(recorder) = recorder else {
+        return fut.await;
+    };
+    let operation = Operation {
+        worker,
+        tx_hash: tx_hash.to_string(),
+        from: from.to_string(),
+        to: to.to_string(),
+        amount,
+        attempt: 0,
+        invoked_ns: 0,
+        completed_ns: 0,
+        reads: Vec::new(),
+        writes: Vec::new(),
+        result: Completion::Commit,
+    };
+    CONTEXT.scope(Context { recorder: recorder.clone(), operation: RefCell::new(operation) }, fut).await
+}
+
+/// Marks the start of `attempt`, before its transaction begins
+pub fn invoke(attempt: u32) {
+    let _ = CONTEXT.try_with(|ctx| {
+        let mut op = ctx.operation.borrow_mut();
+        op.attempt = attempt;
+        op.invoked_ns = ctx.recorder.now_ns();
+        op.reads.clear();
+        op.writes.clear();
+    });
+}
+
+/// Marks the end of the attempt in flight and writes it out
+pub fn complete(res: &Result<TransferOutcome, Error>) {
+    let _ = CONTEXT.try_with(|ctx| {
+        let mut op = ctx.operation.borrow_mut();
+        op.completed_ns = ctx.recorder.now_ns();
+        op.result = Completion::of(res);
+        if let Err(e) = ctx.recorder.record(&op) {
+            tracing::error!("Failed to record operation: {}", e);
+        }
+    });
+}
+
+/// Notes that the attempt in flight read `balance` at `version` for `address`
+pub fn read(address: &str, balance: i64, version: i64) {
+    let _ = CONTEXT.try_with(|ctx| ctx.operation.borrow_mut().reads.push(Access { address: address.to_string(), balance, version }));
+}
+
+/// Notes that the attempt in flight wrote `balance` as `version` for `address`
+pub fn write(address: &str, balance: i64, version: i64) {
+    let _ = CONTEXT.try_with(|ctx| ctx.operation.borrow_mut().writes.push(Access { address: address.to_string(), balance, version }));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Rejection;
+
+    #[tokio::test]
+    async fn test_records_each_attempt() {
+        let path = std::env::temp_dir().join(format!("history-{}.jsonl", std::process::id()));
+        let recorder = Recorder::create(&path).unwrap();
+
+        scope(Some(&recorder), 7, "ab", "0x0", "0x1", 3, async {
+            invoke(1);
+            read("0x0", 10, 0);
+            complete(&Err(Error::Conflict("0x0".to_string())));
+            invoke(2);
+            read("0x0", 7, 1);
+            write("0x0", 4, 2);
+            write("0x1", 13, 1);
+            complete(&Ok(TransferOutcome::Applied { from_balance: 4, to_balance: 13 }));
+        })
+        .await;
+        // Outside a scope nothing is recorded
+        invoke(1);
+        complete(&Ok(TransferOutcome::Rejected { reason: Rejection::InsufficientFunds("0x0".to_string()) }));
+        recorder.flush().unwrap();
+
+        let ops = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[0].attempt, 1);
+        assert_eq!(ops[0].result, Completion::Abort { reason: Error::Conflict("0x0".to_string()).to_string() });
+        assert!(ops[0].writes.is_empty());
+        assert_eq!(ops[1].worker, 7);
+        assert_eq!(ops[1].reads, vec![Access { address: "0x0".to_string(), balance: 7, version: 1 }]);
+        assert_eq!(ops[1].writes.len(), 2);
+        assert_eq!(ops[1].result, Completion::Commit);
+        assert!(ops[1].invoked_ns >= ops[0].completed_ns && ops[1].completed_ns >= ops[1].invoked_ns);
+    }
+}
@@ -0,0 +1,265 @@
+//! Steps real transfers through a scripted interleaving, one checkpoint at a time
+
+use std::{sync::Arc, time::Duration};
+
+use sqlx::PgPool;
+use tokio::{
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
+
+use crate::{
+    checkpoint::{self, Checkpoint, CheckpointFuture, Observer},
+    fixtures,
+    history::{self, Recorder},
+    strategy::TransferStrategy,
+    Error, Ledger, TransferOutcome,
+};
+
+/// Where a transfer stopped after a step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Paused at a checkpoint, holding whatever its transaction holds
+    Paused(Checkpoint),
+    /// Waiting for a lock held by another transaction
+    Blocked,
+    /// The transfer returned, `finish` hands out what it returned
+    Done,
+}
+
+enum Event {
+    Began(i32),
+    Reached(Checkpoint),
+}
+
+/// The observer side of a stepped transfer, it waits at every checkpoint until the harness lets it go
+struct Stepper {
+    events: mpsc::UnboundedSender<Event>,
+    resume: Mutex<mpsc::UnboundedReceiver<()>>,
+}
+
+impl Observer for Stepper {
+    fn began(&self, pid: i32) {
+        let _ = self.events.send(Event::Began(pid));
+    }
+
+    fn reached(&self, checkpoint: Checkpoint) -> CheckpointFuture<'_> {
+        Box::pin(async move {
+            let _ = self.events.send(Event::Reached(checkpoint));
+            self.resume.lock().await.recv().await;
+            Ok(())
+        })
+    }
+}
+
+/// The harness side of a stepped transfer
+struct Transfer {
+    events: mpsc::UnboundedReceiver<Event>,
+    resume: mpsc::UnboundedSender<()>,
+    task: Option<JoinHandle<Result<TransferOutcome, Error>>>,
+    done: bool,
+    pid: Option<i32>,
+    /// Whether it waits for `resume`, at the start or at a checkpoint
+    paused: bool,
+}
+
+/// Transfers that only run when stepped, so their statements interleave exactly as scripted
+pub struct Interleaving {
+    ledger: Ledger,
+    transfers: Vec<Transfer>,
+    recorder: Option<Recorder>,
+}
+
+impl Interleaving {
+    /// Transfers run on `pool` at the server's default isolation, without retries
+    pub fn new(pool: &PgPool) -> Self {
+        Interleaving { ledger: fixtures::ledger(pool), transfers: Vec::new(), recorder: None }
+    }
+
+    /// Records every attempt of the transfers spawned from now on with `recorder`, each transfer
+    /// as a worker of its own
+    pub fn record(&mut self, recorder: &Recorder) {
+        self.recorder = Some(recorder.clone());
+    }
+
+    /// Adds a transfer with `strategy`, it does not start before its first step
+    pub fn spawn(&mut self, strategy: &'static dyn TransferStrategy, tx_hash: &str, from: &str, to: &str, amount: u64) -> usize {
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let (resume, resume_rx) = mpsc::unbounded_channel();
+        let stepper = Arc::new(Stepper { events: events_tx, resume: Mutex::new(resume_rx) });
+        let ledger = self.ledger.clone();
+        let (recorder, worker) = (self.recorder.clone(), self.transfers.len() as u64);
+        let (tx_hash, from, to) = (tx_hash.to_string(), from.to_string(), to.to_string());
+        let task = tokio::spawn(async move {
+            stepper.resume.lock().await.recv().await;
+            let transfer = strategy.transfer(&ledger, &tx_hash, &from, &to, amount);
+            let transfer = history::scope(recorder.as_ref(), worker, &tx_hash, &from, &to, amount, transfer);
+            checkpoint::scope(Some(stepper), transfer).await
+        });
+        self.transfers.push(Transfer { events, resume, task: Some(task), done: false, pid: None, paused: true });
+        self.transfers.len() - 1
+    }
+
+    /// Lets transfer `t` run until it pauses at `checkpoint` or the first one after it, blocks
+    /// on a lock, or returns. A blocked transfer carries on by itself once the lock is released
+    pub async fn run_until(&mut self, t: usize, checkpoint: Checkpoint) -> Step {
+        let pool = self.ledger.pool.clone();
+        let transfer = &mut self.transfers[t];
+        if transfer.done {
+            return Step::Done;
+        }
+        loop {
+            if transfer.paused {
+                transfer.paused = false;
+                let _ = transfer.resume.send(());
+            }
+            let event = loop {
+                tokio::select! {
+                    event = transfer.events.recv() => break event,
+                    _ = tokio::time::sleep(Duration::from_millis(10)) => {
+                        if let Some(pid) = transfer.pid {
+                            if waiting_for_lock(&pool, pid).await {
+                                return Step::Blocked;
+                            }
+                        }
+                    }
+                }
+            };
+            match event {
+                Some(Event::Began(pid)) => transfer.pid = Some(pid),
+                Some(Event::Reached(reached)) => {
+                    transfer.paused = true;
+                    if reached >= checkpoint {
+                        return Step::Paused(reached);
+                    }
+                }
+                None => {
+                    transfer.done = true;
+                    return Step::Done;
+                }
+            }
+        }
+    }
+
+    /// Backend serving the transaction transfer `t` is in, once it began one
+    pub fn pid(&self, t: usize) -> Option<i32> {
+        self.transfers[t].pid
+    }
+
+    /// Drops transfer `t` wherever it is, and with it its transaction, without commit
+    pub async fn abort(&mut self, t: usize) {
+        let task = self.transfers[t].task.take().expect("a transfer is finished once");
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+        self.transfers[t].done = true;
+    }
+
+    /// Lets transfer `t` run to the end and returns what it returned. Panics if it stays blocked
+    /// on a lock, nothing else would release it
+    pub async fn finish(&mut self, t: usize) -> Result<TransferOutcome, Error> {
+        // A transaction that was just released by a commit can still show up as waiting for a moment
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        loop {
+            match self.run_until(t, Checkpoint::BeforeCommit).await {
+                Step::Paused(_) => continue,
+                Step::Blocked if tokio::time::Instant::now() < deadline => continue,
+                Step::Blocked => panic!("transfer {} is blocked on a lock", t),
+                Step::Done => break,
+            }
+        }
+        let task = self.transfers[t].task.take().expect("a transfer is finished once");
+        task.await.expect("transfer panicked")
+    }
+}
+
+async fn waiting_for_lock(pool: &PgPool, pid: i32) -> bool {
+    sqlx::query_scalar!(
+        r#"
+        SELECT wait_event_type = 'Lock' AS "waiting!"
+        FROM pg_stat_activity
+        WHERE pid = $1
+        "#,
+        pid
+    )
+    .fetch_optional(pool)
+    .await
+    .expect("Failed to query pg_stat_activity")
+    .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        fixtures::{accounts, balance},
+        strategy::STRATEGIES,
+        verify::ledger_consistency_verify,
+        Bookkeeping, Rejection,
+    };
+
+    struct Race {
+        steps: Vec<Step>,
+        first: Result<TransferOutcome, Error>,
+        second: Result<TransferOutcome, Error>,
+    }
+
+    /// Two transfers emptying 0x0: T1 reads the balance, T2 reads it, T1 updates, T2 updates,
+    /// then both commit
+    async fn race(pool: &PgPool, strategy: &'static dyn TransferStrategy) -> Race {
+        accounts(pool, &[("0x0", 3), ("0x1", 0), ("0x2", 0)]).await;
+
+        let mut run = Interleaving::new(pool);
+        let t1 = run.spawn(strategy, "1", "0x0", "0x1", 3);
+        let t2 = run.spawn(strategy, "2", "0x0", "0x2", 3);
+        let steps = vec![
+            run.run_until(t1, Checkpoint::AfterReadBalance).await,
+            run.run_until(t2, Checkpoint::AfterReadBalance).await,
+            run.run_until(t1, Checkpoint::AfterCredit).await,
+            run.run_until(t2, Checkpoint::AfterCredit).await,
+        ];
+        let first = run.finish(t1).await;
+        let second = run.finish(t2).await;
+        Race { steps, first, second }
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_bad_transfer_loses_an_update(pool: PgPool) {
+        let bad = crate::strategy::strategy("bad").unwrap();
+        let race = race(&pool, bad).await;
+
+        // Both passed the check on a balance of 3, T2's debit then waited for T1's row lock
+        assert_eq!(
+            race.steps,
+            vec![
+                Step::Paused(Checkpoint::AfterReadBalance),
+                Step::Paused(Checkpoint::AfterReadBalance),
+                Step::Paused(Checkpoint::AfterCredit),
+                Step::Blocked,
+            ]
+        );
+        assert_eq!(race.first.unwrap(), TransferOutcome::Applied { from_balance: 0, to_balance: 3 });
+        assert_eq!(race.second.unwrap(), TransferOutcome::Applied { from_balance: -3, to_balance: 3 });
+        assert_eq!(balance(&pool, "0x0").await, -3);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_strategies_under_the_same_interleaving(pool: PgPool) {
+        for &strategy in STRATEGIES {
+            let Race { first, second, .. } = race(&pool, strategy).await;
+            let report = ledger_consistency_verify(&pool, Bookkeeping::SingleEntry).await.unwrap();
+            assert_eq!(report.is_consistent(), strategy.expected_consistent(), "{}", strategy.name());
+            if !strategy.expected_consistent() {
+                continue;
+            }
+
+            // T1 got there first, T2 either saw the empty balance or was aborted
+            assert_eq!(first.unwrap(), TransferOutcome::Applied { from_balance: 0, to_balance: 3 }, "{}", strategy.name());
+            match second {
+                Ok(TransferOutcome::Rejected { reason: Rejection::InsufficientFunds(address) }) => assert_eq!(address, "0x0"),
+                Err(e) => assert!(e.is_retryable(), "{}: {:?}", strategy.name(), e),
+                other => panic!("{}: {:?}", strategy.name(), other),
+            }
+            assert_eq!(balance(&pool, "0x0").await, 0, "{}", strategy.name());
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Concurrent transfers between Postgres accounts, done right and done wrong, and the tools to
+//! tell the two apart: a consistency verifier, history recording and anomaly detection
+//!
+//! Transfers run through a [`Ledger`] with one of the [`strategy::STRATEGIES`], e.g.
+//! `strategy::strategy("good")`, or by calling a function of [`transfer`] in a transaction of
+//! your own
+
+use std::fmt;
+
+use sqlx::{Executor, PgPool, Transaction};
+
+pub mod anomaly;
+pub mod checkpoint;
+pub mod dependency;
+pub mod error;
+pub mod fault;
+#[cfg(test)]
+mod fixtures;
+pub mod history;
+#[cfg(test)]
+mod interleave;
+pub mod retry;
+pub mod run;
+pub mod schema;
+pub mod strategy;
+pub mod transfer;
+pub mod verify;
+pub mod workload;
+
+pub use error::Error;
+pub use schema::{add_account, clean_up};
+pub use transfer::{RecordedTransfer, Rejection, TransferOutcome};
+
+use retry::RetryPolicy;
+
+/// How a transfer is written to the books
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Bookkeeping {
+    /// One `transaction` row per transfer, balances live in `accounts` only
+    #[default]
+    SingleEntry,
+    /// Additionally a balanced pair of `postings`, from which balances can be rebuilt
+    DoubleEntry,
+}
+
+/// Isolation level of the transactions transfers run in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum IsolationLevel {
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    pub const ALL: [IsolationLevel; 3] = [IsolationLevel::ReadCommitted, IsolationLevel::RepeatableRead, IsolationLevel::Serializable];
+
+    fn set_transaction(&self) -> &'static str {
+        match self {
+            IsolationLevel::ReadCommitted => "SET TRANSACTION ISOLATION LEVEL READ COMMITTED",
+            IsolationLevel::RepeatableRead => "SET TRANSACTION ISOLATION LEVEL REPEATABLE READ",
+            IsolationLevel::Serializable => "SET TRANSACTION ISOLATION LEVEL SERIALIZABLE",
+        }
+    }
+}
+
+impl fmt::Display for IsolationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IsolationLevel::ReadCommitted => write!(f, "read committed"),
+            IsolationLevel::RepeatableRead => write!(f, "repeatable read"),
+            IsolationLevel::Serializable => write!(f, "serializable"),
+        }
+    }
+}
+
+/// Everything a strategy needs to run transfers against the database
+#[derive(Debug, Clone)]
+pub struct Ledger {
+    pub pool: PgPool,
+    pub bookkeeping: Bookkeeping,
+    /// Applied to transfers that fail with a retryable error
+    pub retry: RetryPolicy,
+    /// Level every transfer transaction is set to, the server's default when not given
+    pub isolation: Option<IsolationLevel>,
+}
+
+impl Ledger {
+    /// Begins a transaction at the ledger's isolation level
+    pub async fn begin(&self) -> Result<Transaction<'static, sqlx::Postgres>, Error> {
+        let mut tx = self.pool.begin().await?;
+        if let Some(isolation) = self.isolation {
+            tx.execute(isolation.set_transaction()).await?;
+        }
+        checkpoint::began(&mut tx).await?;
+        Ok(tx)
+    }
+}
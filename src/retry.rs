@@ -0,0 +1,163 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use sqlx::Transaction;
+
+use crate::{history, Error, Ledger, TransferOutcome};
+
+/// How long to wait before the next attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// Always the same delay
+    Fixed(Duration),
+    /// `base * 2^(attempt - 1)` capped at `max`, with full jitter
+    Exponential { base: Duration, max: Duration },
+    /// A random delay between `base` and three times the previous one, capped at `max`.
+    /// Spreads retries out like full jitter but grows with the delays actually taken
+    DecorrelatedJitter { base: Duration, max: Duration },
+}
+
+impl Backoff {
+    /// Delay after the failed `attempt`, `previous` is the delay taken before it
+    pub fn delay(&self, attempt: u32, previous: Duration) -> Duration {
+        let mut rng = rand::thread_rng();
+        match *self {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { base, max } => {
+                let ceiling = base.saturating_mul(1 << attempt.saturating_sub(1).min(16)).min(max);
+                rng.gen_range(Duration::ZERO..=ceiling)
+            }
+            Backoff::DecorrelatedJitter { base, max } => {
+                let ceiling = previous.saturating_mul(3).max(base);
+                rng.gen_range(base..=ceiling).min(max)
+            }
+        }
+    }
+}
+
+/// When and how often `with_retry` runs a transfer again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one
+    pub max_attempts: u32,
+    pub backoff: Backoff,
+    /// No attempt is started after this much time has passed since the first one
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 10,
+            backoff: Backoff::Exponential {
+                base: Duration::from_millis(5),
+                max: Duration::from_millis(500),
+            },
+            deadline: None,
+        }
+    }
+}
+
+/// Runs `transfer` in a fresh transaction of the `ledger` until it returns something that is not a
+/// retryable error or the ledger's retry policy gives up. When more than one attempt was made, the
+/// result is wrapped in `TransferOutcome::Retried` or `Error::Retried` carrying the number of attempts
+/// and why each attempt before the last failed
+pub async fn with_retry<F, Fut>(ledger: &Ledger, mut transfer: F) -> Result<TransferOutcome, Error>
+where
+    F: FnMut(Transaction<'static, sqlx::Postgres>) -> Fut,
+    Fut: Future<Output = Result<TransferOutcome, Error>>,
+{
+    let policy = &ledger.retry;
+    let started = Instant::now();
+    let mut attempt = 1;
+    let mut delay = Duration::ZERO;
+    let mut failures = Vec::new();
+    loop {
+        history::invoke(attempt);
+        let res = match ledger.begin().await {
+            Ok(tx) => transfer(tx).await,
+            Err(e) => Err(e),
+        };
+        history::complete(&res);
+
+        let retry = match &res {
+            Err(e) if e.is_retryable() && attempt < policy.max_attempts => {
+                delay = policy.backoff.delay(attempt, delay);
+                policy.deadline.is_none_or(|deadline| started.elapsed() + delay < deadline)
+            }
+            _ => false,
+        };
+        if !retry {
+            return match res {
+                Ok(outcome) if attempt > 1 => {
+                    Ok(TransferOutcome::Retried { attempts: attempt, failures, outcome: Box::new(outcome) })
+                }
+                Err(e) if attempt > 1 => Err(Error::Retried { attempts: attempt, failures, source: Box::new(e) }),
+                res => res,
+            };
+        }
+
+        if let Err(e) = &res {
+            tracing::debug!("{} on attempt {}, retrying in {:?}", e, attempt, delay);
+            failures.extend(e.failure());
+        }
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{error::Failure, fixtures};
+    use sqlx::PgPool;
+
+    #[test]
+    fn test_backoff_bounds() {
+        let base = Duration::from_millis(5);
+        let max = Duration::from_millis(100);
+
+        assert_eq!(Backoff::Fixed(base).delay(7, max), base);
+
+        let exponential = Backoff::Exponential { base, max };
+        assert!(exponential.delay(0, Duration::ZERO) <= base);
+        for attempt in 1..20 {
+            let ceiling = (base * 2u32.pow((attempt - 1).min(16))).min(max);
+            assert!(exponential.delay(attempt, Duration::ZERO) <= ceiling);
+        }
+
+        let decorrelated = Backoff::DecorrelatedJitter { base, max };
+        let mut previous = Duration::ZERO;
+        for attempt in 1..20 {
+            let delay = decorrelated.delay(attempt, previous);
+            assert!(delay >= base && delay <= max && delay <= (previous * 3).max(base));
+            previous = delay;
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_retried_records_why_each_attempt_failed(pool: PgPool) {
+        let ledger = Ledger {
+            retry: RetryPolicy { max_attempts: 3, backoff: Backoff::Fixed(Duration::ZERO), deadline: None },
+            ..fixtures::ledger(&pool)
+        };
+        let mut attempt = 0;
+        let res = with_retry(&ledger, |_tx| {
+            attempt += 1;
+            let res = match attempt {
+                1 => Err(Error::Deadlock(sqlx::Error::Protocol("deadlock".to_string()))),
+                2 => Err(Error::Conflict("0x0".to_string())),
+                _ => Ok(TransferOutcome::Applied { from_balance: 0, to_balance: 3 }),
+            };
+            async move { res }
+        })
+        .await;
+
+        let Ok(TransferOutcome::Retried { attempts, failures, .. }) = res else { panic!("{:?}", res) };
+        assert_eq!(attempts, 3);
+        assert_eq!(failures, vec![Failure::Deadlock, Failure::Conflict]);
+    }
+}
@@ -0,0 +1,338 @@
+//! Runs a workload against the ledger with one strategy and counts what happened to its transfers
+
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use sqlx::PgPool;
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{
+    add_account,
+    checkpoint::{self, Hook, Hooks},
+    clean_up,
+    error::Failure,
+    fault,
+    history::{self, Recorder},
+    retry::RetryPolicy,
+    strategy::TransferStrategy,
+    workload::PairGenerator,
+    Bookkeeping, Error, IsolationLevel, Ledger, Rejection, TransferOutcome,
+};
+
+/// How a run sets up its accounts and submits its transfers
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// Number of accounts created before the run, `0x0` onwards
+    pub accounts: u64,
+    /// Starting balance of every account
+    pub initial_balance: u64,
+    /// Number of transfers to submit
+    pub transfers: u64,
+    /// Amount moved by each transfer
+    pub amount: u64,
+    pub bookkeeping: Bookkeeping,
+    pub retry: RetryPolicy,
+    /// Resubmit every N-th transfer with the tx_hash, sender and recipient of the one before it
+    pub replay_every: Option<u64>,
+    /// Maximum number of transfers in flight
+    pub concurrency: usize,
+    /// Actions run at the checkpoints of every transfer
+    pub hooks: Vec<Hook>,
+    /// Terminate a backend of the run that is inside a transaction this often, see `fault::Killer`
+    /// for what the pool needs
+    pub kill_interval: Option<Duration>,
+    /// Chance that a transfer is dropped by the client, see `fault::maybe_drop`
+    pub drop_rate: f64,
+    /// A dropped transfer is dropped after a random delay of up to this
+    pub drop_within: Duration,
+}
+
+/// Failed attempts of one run by cause, the ones that were retried as well as the last ones
+#[derive(Debug, Default, serde::Serialize)]
+pub struct FailedAttempts {
+    pub serialization_failures: usize,
+    pub deadlocks: usize,
+    pub lock_not_available: usize,
+    pub connections_lost: usize,
+    pub conflicts: usize,
+}
+
+impl FailedAttempts {
+    fn add(&mut self, failure: Failure) {
+        match failure {
+            Failure::SerializationFailure => self.serialization_failures += 1,
+            Failure::Deadlock => self.deadlocks += 1,
+            Failure::LockNotAvailable => self.lock_not_available += 1,
+            Failure::ConnectionLost => self.connections_lost += 1,
+            Failure::Conflict => self.conflicts += 1,
+        }
+    }
+}
+
+/// What happened to the transfers of one run
+#[derive(Debug, Default, serde::Serialize)]
+pub struct RunSummary {
+    pub total: usize,
+    pub applied: usize,
+    pub duplicates: usize,
+    pub insufficient_funds: usize,
+    pub account_not_found: usize,
+    /// Transfers that needed more than one attempt, whatever they ended in
+    pub retried: usize,
+    /// Attempts made by the retried transfers, including their last
+    pub retry_attempts: u64,
+    pub conflicts: usize,
+    pub serialization_failures: usize,
+    pub deadlocks: usize,
+    pub connections_lost: usize,
+    /// Transfers a `rollback` hook ended
+    pub rolled_back: usize,
+    /// Transfers whose task panicked, e.g. at a `panic` hook
+    pub panicked: usize,
+    /// Transfers the client dropped before they returned
+    pub dropped: usize,
+    pub other_errors: usize,
+    /// Every failed attempt, including those of transfers that succeeded on a later one
+    pub failed_attempts: FailedAttempts,
+    /// Backends terminated while the transfers ran
+    pub backends_terminated: u64,
+}
+
+impl RunSummary {
+    /// Counts the result of one transfer
+    pub fn add(&mut self, res: &Result<TransferOutcome, Error>) {
+        self.total += 1;
+        match res {
+            Ok(outcome) => self.add_outcome(outcome),
+            Err(e) => self.add_error(e),
+        }
+    }
+
+    fn add_error(&mut self, e: &Error) {
+        if let Some(failure) = e.failure() {
+            self.failed_attempts.add(failure);
+        }
+        match e {
+            Error::Conflict(_) => self.conflicts += 1,
+            Error::SerializationFailure(_) => self.serialization_failures += 1,
+            Error::Deadlock(_) => self.deadlocks += 1,
+            Error::ConnectionLost(_) => self.connections_lost += 1,
+            Error::RolledBack(_) => self.rolled_back += 1,
+            Error::Retried { attempts, failures, source } => {
+                self.retried += 1;
+                self.retry_attempts += *attempts as u64;
+                failures.iter().for_each(|failure| self.failed_attempts.add(*failure));
+                self.add_error(source);
+            }
+            _ => self.other_errors += 1,
+        }
+    }
+
+    fn add_outcome(&mut self, outcome: &TransferOutcome) {
+        match outcome {
+            TransferOutcome::Applied { .. } => self.applied += 1,
+            TransferOutcome::Duplicate { .. } => self.duplicates += 1,
+            TransferOutcome::Rejected { reason: Rejection::InsufficientFunds(_) } => self.insufficient_funds += 1,
+            TransferOutcome::Rejected { reason: Rejection::AccountNotFound(_) } => self.account_not_found += 1,
+            TransferOutcome::Retried { attempts, failures, outcome } => {
+                self.retried += 1;
+                self.retry_attempts += *attempts as u64;
+                failures.iter().for_each(|failure| self.failed_attempts.add(*failure));
+                self.add_outcome(outcome);
+            }
+        }
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Transfers: {}", self.total)?;
+        writeln!(f, "  applied: {}", self.applied)?;
+        writeln!(f, "  duplicate: {}", self.duplicates)?;
+        writeln!(f, "  rejected, insufficient funds: {}", self.insufficient_funds)?;
+        writeln!(f, "  rejected, account not found: {}", self.account_not_found)?;
+        writeln!(f, "  failed, version conflict: {}", self.conflicts)?;
+        writeln!(f, "  failed, serialization failure: {}", self.serialization_failures)?;
+        writeln!(f, "  failed, deadlock: {}", self.deadlocks)?;
+        writeln!(f, "  failed, connection lost: {}", self.connections_lost)?;
+        writeln!(f, "  failed, rolled back by hook: {}", self.rolled_back)?;
+        writeln!(f, "  failed, panicked: {}", self.panicked)?;
+        writeln!(f, "  failed, dropped: {}", self.dropped)?;
+        writeln!(f, "  failed, other: {}", self.other_errors)?;
+        writeln!(f, "  retried: {} ({} attempts)", self.retried, self.retry_attempts)?;
+        let attempts = &self.failed_attempts;
+        writeln!(f, "Failed attempts:")?;
+        writeln!(f, "  version conflict: {}", attempts.conflicts)?;
+        writeln!(f, "  serialization failure: {}", attempts.serialization_failures)?;
+        writeln!(f, "  deadlock: {}", attempts.deadlocks)?;
+        writeln!(f, "  lock not available: {}", attempts.lock_not_available)?;
+        writeln!(f, "  connection lost: {}", attempts.connections_lost)?;
+        write!(f, "Backends terminated: {}", self.backends_terminated)
+    }
+}
+
+/// One pass of the workload with one strategy
+pub struct Run {
+    pub summary: RunSummary,
+    /// Time each transfer took, retries included
+    pub latencies: Vec<Duration>,
+    /// Wall clock time of the whole pass
+    pub elapsed: Duration,
+}
+
+/// Resets the accounts, then submits every transfer of the workload with `strategy`,
+/// keeping at most `options.concurrency` of them in flight at `isolation`. Every attempt is recorded
+/// when `recorder` is given, and the hooks of `options` run at the checkpoints of every transfer.
+/// Faults are injected as asked for by `options`. Fails when the accounts cannot be set up, the
+/// killer cannot be started or fails, or a transfer task is cancelled
+pub async fn run_workload(
+    options: &RunOptions,
+    pool: &PgPool,
+    pairs: &PairGenerator,
+    strategy: &'static dyn TransferStrategy,
+    isolation: Option<IsolationLevel>,
+    recorder: Option<&Recorder>,
+) -> Result<Run, Error> {
+    // clean up the database
+    clean_up(pool).await?;
+    // add some accounts
+    for i in 0..options.accounts {
+        let address = format!("0x{0:x}", i);
+        add_account(pool, &address, options.initial_balance).await?;
+    }
+
+    let ledger = Ledger { pool: pool.clone(), bookkeeping: options.bookkeeping, retry: options.retry, isolation };
+    // Shared by all transfers of the run, so their barriers fill up
+    let hooks: Option<Arc<dyn checkpoint::Observer>> =
+        (!options.hooks.is_empty()).then(|| Arc::new(Hooks::new(&options.hooks)) as _);
+    // Waiting for a permit here rather than for a connection in the pool keeps queueing out of
+    // the latencies, and keeps the pool's acquire timeout from failing transfers of long runs
+    let permits = Arc::new(Semaphore::new(options.concurrency));
+    let killer = match options.kill_interval {
+        Some(interval) => Some(fault::Killer::spawn(pool, interval).await?),
+        None => None,
+    };
+    let (drop_rate, drop_within) = (options.drop_rate, options.drop_within);
+    let started = Instant::now();
+    let mut futs = JoinSet::new();
+    for worker in 0..options.transfers {
+        let ledger = ledger.clone();
+        let recorder = recorder.cloned();
+        let hooks = hooks.clone();
+        // A replay reuses the previous transfer's number, so it gets the same tx_hash and pair
+        let i = match options.replay_every {
+            Some(n) if worker % n == n - 1 => worker - 1,
+            _ => worker,
+        };
+        let (from, to) = pairs.pair(i);
+        let from = format!("0x{0:x}", from);
+        let to = format!("0x{0:x}", to);
+        let amount = options.amount;
+        let permit = permits.clone().acquire_owned().await.expect("semaphore is never closed");
+        futs.spawn(async move {
+            let tx_hash = format!("{:x}", i);
+            let started = Instant::now();
+            let transfer = strategy.transfer(&ledger, &tx_hash, &from, &to, amount);
+            let transfer = history::scope(recorder.as_ref(), worker, &tx_hash, &from, &to, amount, transfer);
+            let res = fault::maybe_drop(drop_rate, drop_within, checkpoint::scope(hooks, transfer)).await;
+            let latency = started.elapsed();
+            drop(permit);
+            if let Some(Err(e)) = &res {
+                tracing::error!("Error: {:?}", e);
+            }
+            (res, latency)
+        });
+    }
+
+    let mut summary = RunSummary::default();
+    let mut latencies = Vec::with_capacity(options.transfers as usize);
+    while let Some(joined) = futs.join_next().await {
+        match joined {
+            Ok((Some(res), latency)) => {
+                summary.add(&res);
+                latencies.push(latency);
+            }
+            Ok((None, _)) => {
+                summary.total += 1;
+                summary.dropped += 1;
+            }
+            // Dropping the transaction of a panicked transfer rolls it back
+            Err(e) if e.is_panic() => {
+                summary.total += 1;
+                summary.panicked += 1;
+            }
+            // Dropping the killer stops it
+            Err(e) => return Err(Error::Other(format!("transfer task failed: {}", e))),
+        }
+    }
+    if let Some(killer) = killer {
+        summary.backends_terminated = killer.stop().await?;
+    }
+    Ok(Run { summary, latencies, elapsed: started.elapsed() })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{retry::Backoff, strategy::strategy, verify::ledger_consistency_verify, workload::Workload};
+    use sqlx::postgres::PgPoolOptions;
+
+    #[test]
+    fn test_summary_counts_every_failed_attempt() {
+        let mut summary = RunSummary::default();
+        let applied = Box::new(TransferOutcome::Applied { from_balance: 0, to_balance: 3 });
+        summary.add(&Ok(TransferOutcome::Retried {
+            attempts: 3,
+            failures: vec![Failure::Deadlock, Failure::SerializationFailure],
+            outcome: applied,
+        }));
+        summary.add(&Err(Error::Retried {
+            attempts: 2,
+            failures: vec![Failure::Deadlock],
+            source: Box::new(Error::Conflict("0x0".to_string())),
+        }));
+        summary.add(&Err(Error::Conflict("0x1".to_string())));
+
+        assert_eq!((summary.applied, summary.deadlocks, summary.conflicts), (1, 0, 2));
+        assert_eq!(summary.failed_attempts.deadlocks, 2);
+        assert_eq!(summary.failed_attempts.serialization_failures, 1);
+        assert_eq!(summary.failed_attempts.conflicts, 2);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_workload_survives_faults(pool: PgPool) {
+        let options = pool.connect_options().as_ref().clone().application_name("test_workload_survives_faults");
+        let pool = PgPoolOptions::new().connect_with(options).await.unwrap();
+        let options = RunOptions {
+            accounts: 5,
+            initial_balance: 1000,
+            transfers: 300,
+            amount: 3,
+            bookkeeping: Bookkeeping::SingleEntry,
+            retry: RetryPolicy {
+                max_attempts: 10,
+                backoff: Backoff::Exponential { base: Duration::from_millis(5), max: Duration::from_millis(20) },
+                deadline: None,
+            },
+            replay_every: None,
+            concurrency: 16,
+            hooks: Vec::new(),
+            kill_interval: Some(Duration::from_millis(5)),
+            drop_rate: 0.2,
+            drop_within: Duration::from_millis(5),
+        };
+        let pairs = PairGenerator::new(Workload::HotSender, options.accounts, 1, 1.0).unwrap();
+        let good = strategy("good").unwrap();
+        let run = run_workload(&options, &pool, &pairs, good, None, None).await.unwrap();
+
+        assert_eq!(run.summary.total, 300);
+        assert!(run.summary.dropped > 0);
+        assert!(run.summary.backends_terminated > 0);
+        assert_eq!(run.summary.other_errors, 0, "{}", run.summary);
+        let report = ledger_consistency_verify(&pool, Bookkeeping::SingleEntry).await.unwrap();
+        assert!(report.is_consistent(), "{}", report);
+    }
+}
@@ -0,0 +1,75 @@
+//! The accounts and the books, as laid out by the migrations
+
+use sqlx::Executor;
+
+/// Removes every account, transaction and posting
+pub async fn clean_up<'a, E>(executor: E) -> sqlx::Result<u64>
+where E: Executor<'a, Database = sqlx::Postgres>
+{
+    sqlx::query!(
+        r#"
+        truncate postings, transaction, accounts
+        "#,
+    )
+    .execute(executor)
+    .await
+    .map(|res| res.rows_affected())
+}
+
+/// Creates an account holding `initial`, unless `address` already exists.
+/// Returns the number of accounts created
+pub async fn add_account<'a, E>(executor: E, address: &str, initial: u64) -> sqlx::Result<u64>
+where E: Executor<'a, Database = sqlx::Postgres>
+{
+    sqlx::query!(
+        r#"
+        INSERT INTO accounts (address, balance, initial_balance)
+        VALUES ($1, $2, $2)
+        ON CONFLICT DO NOTHING
+        "#,
+        address,
+        initial as i64
+    )
+    .execute(executor)
+    .await
+    .map(|res| res.rows_affected())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{fixtures::accounts, transfer::good_transfer, Bookkeeping};
+    use sqlx::PgPool;
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_add_account(pool: PgPool) {
+        let address = "0x1234567890abcdef".to_string();
+        let rows_affected = add_account(&pool, &address, 1000).await.unwrap();
+        assert_eq!(rows_affected, 1);
+
+        let rows_affected = add_account(&pool, &address, 1000).await.unwrap();
+        assert_eq!(rows_affected, 0);
+
+        let row = sqlx::query!("SELECT balance, initial_balance FROM accounts WHERE address = $1", address)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!((row.balance, row.initial_balance), (1000, 1000));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_clean_up(pool: PgPool) {
+        accounts(&pool, &[("0x0", 10), ("0x1", 10)]).await;
+        let tx = pool.begin().await.unwrap();
+        good_transfer(tx, Bookkeeping::DoubleEntry, "1", "0x0", "0x1", 3).await.unwrap();
+
+        clean_up(&pool).await.unwrap();
+        let count = |table: &'static str| {
+            let pool = pool.clone();
+            async move { sqlx::query_scalar::<_, i64>(&format!("SELECT count(*) FROM {}", table)).fetch_one(&pool).await.unwrap() }
+        };
+        assert_eq!(count("accounts").await, 0);
+        assert_eq!(count("transaction").await, 0);
+        assert_eq!(count("postings").await, 0);
+    }
+}
@@ -0,0 +1,205 @@
+use std::{future::Future, pin::Pin};
+
+use crate::{
+    retry::with_retry,
+    transfer::{advisory_lock_transfer, bad_transfer, good_transfer, occ_transfer, ordered_transfer, pessimistic_transfer},
+    Error, IsolationLevel, Ledger, TransferOutcome,
+};
+
+pub type TransferFuture<'a> = Pin<Box<dyn Future<Output = Result<TransferOutcome, Error>> + Send + 'a>>;
+
+/// One way of moving `amount` from `from` to `to` under concurrency
+pub trait TransferStrategy: Send + Sync {
+    /// Name used to pick the strategy on the command line
+    fn name(&self) -> &'static str;
+    /// How the strategy keeps concurrent transfers apart, or why it does not
+    fn description(&self) -> &'static str;
+    /// Whether the ledger is expected to stay consistent under concurrent transfers
+    fn expected_consistent(&self) -> bool;
+    /// Level the strategy always runs at, whatever the ledger's isolation level is
+    fn isolation(&self) -> Option<IsolationLevel> {
+        None
+    }
+    /// Runs one transfer at the ledger's isolation level, retrying it in a fresh transaction
+    /// according to the ledger's retry policy
+    fn transfer<'a>(&'a self, ledger: &'a Ledger, tx_hash: &'a str, from: &'a str, to: &'a str, amount: u64) -> TransferFuture<'a>;
+}
+
+/// Every strategy, in the order they are presented
+pub static STRATEGIES: &[&dyn TransferStrategy] = &[
+    &BadTransfer,
+    &GoodTransfer,
+    &PessimisticTransfer,
+    &SerializableTransfer,
+    &OccTransfer,
+    &AdvisoryLockTransfer,
+    &OrderedTransfer,
+];
+
+/// Looks a strategy up by its `name`
+pub fn strategy(name: &str) -> Option<&'static dyn TransferStrategy> {
+    STRATEGIES.iter().copied().find(|s| s.name() == name)
+}
+
+pub struct BadTransfer;
+
+impl TransferStrategy for BadTransfer {
+    fn name(&self) -> &'static str {
+        "bad"
+    }
+
+    fn description(&self) -> &'static str {
+        "Checks the balance without a lock, so concurrent transfers pass the check on a stale read and overdraw the sender"
+    }
+
+    fn expected_consistent(&self) -> bool {
+        false
+    }
+
+    fn transfer<'a>(&'a self, ledger: &'a Ledger, tx_hash: &'a str, from: &'a str, to: &'a str, amount: u64) -> TransferFuture<'a> {
+        Box::pin(with_retry(ledger, move |tx| bad_transfer(tx, ledger.bookkeeping, tx_hash, from, to, amount)))
+    }
+}
+
+pub struct GoodTransfer;
+
+impl TransferStrategy for GoodTransfer {
+    fn name(&self) -> &'static str {
+        "good"
+    }
+
+    fn description(&self) -> &'static str {
+        "Folds the balance check into the debit, UPDATE ... WHERE balance >= amount, which re-checks the row after waiting for its lock"
+    }
+
+    fn expected_consistent(&self) -> bool {
+        true
+    }
+
+    fn transfer<'a>(&'a self, ledger: &'a Ledger, tx_hash: &'a str, from: &'a str, to: &'a str, amount: u64) -> TransferFuture<'a> {
+        Box::pin(with_retry(ledger, move |tx| good_transfer(tx, ledger.bookkeeping, tx_hash, from, to, amount)))
+    }
+}
+
+pub struct PessimisticTransfer;
+
+impl TransferStrategy for PessimisticTransfer {
+    fn name(&self) -> &'static str {
+        "pessimistic"
+    }
+
+    fn description(&self) -> &'static str {
+        "Locks the sender with SELECT ... FOR UPDATE before checking its balance, the recipient is locked later by its UPDATE"
+    }
+
+    fn expected_consistent(&self) -> bool {
+        true
+    }
+
+    fn transfer<'a>(&'a self, ledger: &'a Ledger, tx_hash: &'a str, from: &'a str, to: &'a str, amount: u64) -> TransferFuture<'a> {
+        Box::pin(with_retry(ledger, move |tx| pessimistic_transfer(tx, ledger.bookkeeping, tx_hash, from, to, amount)))
+    }
+}
+
+pub struct SerializableTransfer;
+
+impl TransferStrategy for SerializableTransfer {
+    fn name(&self) -> &'static str {
+        "serializable"
+    }
+
+    fn description(&self) -> &'static str {
+        "Runs the unlocked statements at SERIALIZABLE, Postgres aborts conflicting transactions and the retry policy runs them again"
+    }
+
+    fn expected_consistent(&self) -> bool {
+        true
+    }
+
+    /// Whatever level the run asks for, the strategy is the unlocked statements at SERIALIZABLE
+    fn isolation(&self) -> Option<IsolationLevel> {
+        Some(IsolationLevel::Serializable)
+    }
+
+    fn transfer<'a>(&'a self, ledger: &'a Ledger, tx_hash: &'a str, from: &'a str, to: &'a str, amount: u64) -> TransferFuture<'a> {
+        Box::pin(async move {
+            // Postgres aborts whichever transaction would break serializability and the retry policy runs it again
+            let ledger = Ledger { isolation: self.isolation(), ..ledger.clone() };
+            with_retry(&ledger, |tx| bad_transfer(tx, ledger.bookkeeping, tx_hash, from, to, amount)).await
+        })
+    }
+}
+
+pub struct OccTransfer;
+
+impl TransferStrategy for OccTransfer {
+    fn name(&self) -> &'static str {
+        "occ"
+    }
+
+    fn description(&self) -> &'static str {
+        "Takes no lock while reading, the debit only applies if the sender's version is unchanged, otherwise the transfer is a conflict and gets retried"
+    }
+
+    fn expected_consistent(&self) -> bool {
+        true
+    }
+
+    fn transfer<'a>(&'a self, ledger: &'a Ledger, tx_hash: &'a str, from: &'a str, to: &'a str, amount: u64) -> TransferFuture<'a> {
+        Box::pin(with_retry(ledger, move |tx| occ_transfer(tx, ledger.bookkeeping, tx_hash, from, to, amount)))
+    }
+}
+
+pub struct AdvisoryLockTransfer;
+
+impl TransferStrategy for AdvisoryLockTransfer {
+    fn name(&self) -> &'static str {
+        "advisory-lock"
+    }
+
+    fn description(&self) -> &'static str {
+        "Takes pg_advisory_xact_lock on both addresses in sorted order, then runs the unlocked statements"
+    }
+
+    fn expected_consistent(&self) -> bool {
+        true
+    }
+
+    fn transfer<'a>(&'a self, ledger: &'a Ledger, tx_hash: &'a str, from: &'a str, to: &'a str, amount: u64) -> TransferFuture<'a> {
+        Box::pin(with_retry(ledger, move |tx| advisory_lock_transfer(tx, ledger.bookkeeping, tx_hash, from, to, amount)))
+    }
+}
+
+pub struct OrderedTransfer;
+
+impl TransferStrategy for OrderedTransfer {
+    fn name(&self) -> &'static str {
+        "ordered"
+    }
+
+    fn description(&self) -> &'static str {
+        "Locks both accounts with SELECT ... FOR UPDATE in address order, so opposite transfers cannot deadlock"
+    }
+
+    fn expected_consistent(&self) -> bool {
+        true
+    }
+
+    fn transfer<'a>(&'a self, ledger: &'a Ledger, tx_hash: &'a str, from: &'a str, to: &'a str, amount: u64) -> TransferFuture<'a> {
+        Box::pin(with_retry(ledger, move |tx| ordered_transfer(tx, ledger.bookkeeping, tx_hash, from, to, amount)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strategy_lookup() {
+        for s in STRATEGIES {
+            let found = strategy(s.name()).unwrap();
+            assert_eq!(found.description(), s.description());
+        }
+        assert!(strategy("nope").is_none());
+    }
+}
@@ -0,0 +1,568 @@
+//! The transfer functions behind the strategies. Each runs one attempt in the transaction it is
+//! given and commits it when the transfer is applied
+
+use std::fmt;
+
+use sqlx::{PgConnection, Transaction};
+
+use crate::{checkpoint::{self, Checkpoint}, error::Failure, history, Bookkeeping, Error};
+
+/// A transfer as recorded in the `transaction` table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedTransfer {
+    pub tx_hash: String,
+    pub from: String,
+    pub to: String,
+    pub amount: u64,
+}
+
+/// What a transfer did, when it did not fail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferOutcome {
+    /// The money moved and the transfer was recorded. The balances are the sender's right
+    /// after the debit and the recipient's right after the credit
+    Applied { from_balance: i64, to_balance: i64 },
+    /// The `tx_hash` was recorded before, nothing moved this time
+    Duplicate { original: RecordedTransfer },
+    /// The transfer is not allowed, nothing moved and nothing was recorded
+    Rejected { reason: Rejection },
+    /// The transaction had to be run `attempts` times before it ended in `outcome`, `failures`
+    /// are why the attempts before it failed
+    Retried { attempts: u32, failures: Vec<Failure>, outcome: Box<TransferOutcome> },
+}
+
+/// Why a transfer was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    InsufficientFunds(String),
+    AccountNotFound(String),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::InsufficientFunds(address) => write!(f, "Insufficient funds account({})", address),
+            Rejection::AccountNotFound(address) => write!(f, "Account not found: {}", address),
+        }
+    }
+}
+
+/// Inserts the transaction row, and its postings when keeping double-entry books.
+/// If `tx_hash` was already recorded nothing is written and the original transfer is returned.
+/// A concurrent insert of the same `tx_hash` blocks here until the other transaction finishes.
+/// Transfers call it first, before reading or touching any balance, so the `tx_hash` is claimed
+/// for the rest of the transaction and a replay stops before it moves anything
+async fn record_transaction(conn: &mut PgConnection, bookkeeping: Bookkeeping, tx_hash: &str, from: &str, to: &str, amount: u64) -> Result<Option<RecordedTransfer>, Error>
+{
+    let insert_rows = sqlx::query!(
+        r#"
+        INSERT INTO transaction (tx_hash, from_address, to_address, amount)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT DO NOTHING
+        "#,
+        tx_hash,
+        from,
+        to,
+        amount as i64
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+
+    if insert_rows == 0 {
+        let original = sqlx::query!(
+            r#"
+            SELECT tx_hash, from_address, to_address, amount
+            FROM transaction
+            WHERE tx_hash = $1
+            "#,
+            tx_hash
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+
+        return Ok(Some(RecordedTransfer {
+            tx_hash: original.tx_hash,
+            from: original.from_address,
+            to: original.to_address,
+            amount: original.amount as u64,
+        }));
+    }
+
+    if bookkeeping == Bookkeeping::SingleEntry {
+        return Ok(None);
+    }
+
+    // Credit the sender, debit the recipient, the constraint trigger checks they cancel out at commit
+    sqlx::query!(
+        r#"
+        INSERT INTO postings (tx_hash, address, amount)
+        VALUES ($1, $2, -$4::int8), ($1, $3, $4)
+        "#,
+        tx_hash,
+        from,
+        to,
+        amount as i64
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(None)
+}
+
+/// Records the transfer with `record_transaction`, and returns the outcome of a replay, which
+/// ends the transfer
+async fn claim(conn: &mut PgConnection, bookkeeping: Bookkeeping, tx_hash: &str, from: &str, to: &str, amount: u64) -> Result<Option<TransferOutcome>, Error>
+{
+    let Some(original) = record_transaction(conn, bookkeeping, tx_hash, from, to, amount).await? else {
+        return Ok(None);
+    };
+    tracing::info!("Transaction already exists");
+    Ok(Some(TransferOutcome::Duplicate { original }))
+}
+
+/// Debits `from` without checking its balance, which the caller did, and passes `AfterDebit`.
+/// Returns the new balance
+async fn debit(conn: &mut PgConnection, from: &str, amount: u64) -> Result<i64, Error> {
+    let new_from_row = sqlx::query!(
+        r#"
+        UPDATE accounts
+        SET balance = balance - $1, updated_at = now()
+        WHERE address = $2
+        RETURNING balance, version
+        "#,
+        amount as i64,
+        from
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .map(|row| (row.balance, row.version));
+
+    let Some((new_from_balance, new_from_version)) = new_from_row else {
+        tracing::info!("Failed to update sender account");
+        return Err(Error::Other("Failed to update sender account".to_string()));
+    };
+    history::write(from, new_from_balance, new_from_version);
+    checkpoint::reached(Checkpoint::AfterDebit).await?;
+    Ok(new_from_balance)
+}
+
+/// Credits `to`, which has to exist, and passes `AfterCredit`. Returns the new balance
+async fn credit(conn: &mut PgConnection, to: &str, amount: u64) -> Result<i64, Error> {
+    let new_to_row = sqlx::query!(
+        r#"
+        UPDATE accounts
+        SET balance = balance + $1, updated_at = now()
+        WHERE address = $2
+        RETURNING balance, version
+        "#,
+        amount as i64,
+        to
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .map(|row| (row.balance, row.version));
+
+    let Some((new_to_balance, new_to_version)) = new_to_row else {
+        tracing::info!("Failed to update recipient account");
+        return Err(Error::Other("Failed to update recipient account".to_string()));
+    };
+    history::write(to, new_to_balance, new_to_version);
+    checkpoint::reached(Checkpoint::AfterCredit).await?;
+    Ok(new_to_balance)
+}
+
+/// Checks the balance in the debit itself, `WHERE balance >= amount`, which Postgres re-evaluates
+/// on the latest version of the row once it holds its lock. Creates the recipient if needed
+pub async fn good_transfer<'a>(mut tx: Transaction<'a, sqlx::Postgres>, bookkeeping: Bookkeeping, tx_hash: &str, from: &str, to: &str, amount: u64) -> Result<TransferOutcome, Error>
+{
+    if let Some(duplicate) = claim(&mut tx, bookkeeping, tx_hash, from, to, amount).await? {
+        return Ok(duplicate);
+    }
+
+    let new_from_row = sqlx::query!(
+        r#"
+        UPDATE accounts
+        SET balance = balance - $1, updated_at = now()
+        WHERE address = $2 AND balance >= $1
+        RETURNING balance, version
+        "#,
+        amount as i64,
+        from
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map(|row| (row.balance, row.version));
+
+    let Some((new_from_balance, new_from_version)) = new_from_row else {
+        // The WHERE matches neither a missing sender nor one without enough money
+        let exists = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM accounts WHERE address = $1) AS "exists!"
+            "#,
+            from
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if !exists {
+            tracing::info!("Account not found");
+            return Ok(TransferOutcome::Rejected { reason: Rejection::AccountNotFound(from.to_string()) });
+        }
+        tracing::info!("Insufficient funds");
+        return Ok(TransferOutcome::Rejected { reason: Rejection::InsufficientFunds(from.to_string()) });
+    };
+    // The WHERE checked the balance of the version this write replaced, after waiting for its lock
+    history::read(from, new_from_balance + amount as i64, new_from_version - 1);
+    history::write(from, new_from_balance, new_from_version);
+    checkpoint::reached(Checkpoint::AfterDebit).await?;
+
+    let new_to_row = sqlx::query!(
+        r#"
+        INSERT INTO accounts (address, balance, updated_at)
+        VALUES ($1, $2, now())
+        ON CONFLICT (address) DO UPDATE
+        SET balance = accounts.balance + EXCLUDED.balance, updated_at = now()
+        RETURNING balance, version
+        "#,
+        to,
+        amount as i64,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map(|row| (row.balance, row.version));
+
+    let Some((new_to_balance, new_to_version)) = new_to_row else {
+        tracing::info!("Failed to update recipient account");
+        return Err(Error::Other("Failed to update recipient account".to_string()));
+    };
+    history::write(to, new_to_balance, new_to_version);
+    checkpoint::reached(Checkpoint::AfterCredit).await?;
+
+    checkpoint::reached(Checkpoint::BeforeCommit).await?;
+    tx.commit().await?;
+    Ok(TransferOutcome::Applied { from_balance: new_from_balance, to_balance: new_to_balance })
+}
+
+/// Reads the balance without a lock and debits in a separate statement, so concurrent transfers
+/// can overdraw the sender. Kept to show what goes wrong
+pub async fn bad_transfer<'a>(mut tx: Transaction<'a, sqlx::Postgres>, bookkeeping: Bookkeeping, tx_hash: &str, from: &str, to: &str, amount: u64) -> Result<TransferOutcome, Error>
+{
+    if let Some(duplicate) = claim(&mut tx, bookkeeping, tx_hash, from, to, amount).await? {
+        return Ok(duplicate);
+    }
+
+    // BAD1: No lock on the account balance
+    let from_row = sqlx::query!(
+        r#"
+        SELECT balance, version
+        FROM accounts
+        WHERE address = $1
+        "#,
+        from
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map(|row| (row.balance, row.version));
+
+    let Some((from_balance, from_version)) = from_row else {
+        tracing::info!("Account not found");
+        return Ok(TransferOutcome::Rejected { reason: Rejection::AccountNotFound(from.to_string()) });
+    };
+    history::read(from, from_balance, from_version);
+    checkpoint::reached(Checkpoint::AfterReadBalance).await?;
+
+    if from_balance < amount as i64 {
+        tracing::info!("Insufficient funds");
+        return Ok(TransferOutcome::Rejected { reason: Rejection::InsufficientFunds(from.to_string()) });
+    }
+
+    // BAD1: Because there is no lock on the account balance
+    // BAD1: the balance can be updated by another transaction
+    let new_from_balance = debit(&mut tx, from, amount).await?;
+    let new_to_balance = credit(&mut tx, to, amount).await?;
+
+    checkpoint::reached(Checkpoint::BeforeCommit).await?;
+    tx.commit().await?;
+    Ok(TransferOutcome::Applied { from_balance: new_from_balance, to_balance: new_to_balance })
+}
+
+/// Locks the sender with `SELECT ... FOR UPDATE` before checking its balance
+pub async fn pessimistic_transfer<'a>(mut tx: Transaction<'a, sqlx::Postgres>, bookkeeping: Bookkeeping, tx_hash: &str, from: &str, to: &str, amount: u64) -> Result<TransferOutcome, Error>
+{
+    if let Some(duplicate) = claim(&mut tx, bookkeeping, tx_hash, from, to, amount).await? {
+        return Ok(duplicate);
+    }
+
+    // Same shape as bad_transfer, but FOR UPDATE takes a row lock on the sender,
+    // so concurrent transfers from the same account queue up here until we commit
+    let from_row = sqlx::query!(
+        r#"
+        SELECT balance, version
+        FROM accounts
+        WHERE address = $1
+        FOR UPDATE
+        "#,
+        from
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map(|row| (row.balance, row.version));
+
+    let Some((from_balance, from_version)) = from_row else {
+        tracing::info!("Account not found");
+        return Ok(TransferOutcome::Rejected { reason: Rejection::AccountNotFound(from.to_string()) });
+    };
+    history::read(from, from_balance, from_version);
+    checkpoint::reached(Checkpoint::AfterReadBalance).await?;
+
+    if from_balance < amount as i64 {
+        tracing::info!("Insufficient funds");
+        return Ok(TransferOutcome::Rejected { reason: Rejection::InsufficientFunds(from.to_string()) });
+    }
+
+    // The balance we checked cannot change until commit, the row is ours
+    let new_from_balance = debit(&mut tx, from, amount).await?;
+    let new_to_balance = credit(&mut tx, to, amount).await?;
+
+    checkpoint::reached(Checkpoint::BeforeCommit).await?;
+    tx.commit().await?;
+    Ok(TransferOutcome::Applied { from_balance: new_from_balance, to_balance: new_to_balance })
+}
+
+/// Optimistic concurrency control: no locks are taken while reading, instead the update only
+/// applies if the row still carries the version we read, otherwise the transfer is a conflict
+pub async fn occ_transfer<'a>(mut tx: Transaction<'a, sqlx::Postgres>, bookkeeping: Bookkeeping, tx_hash: &str, from: &str, to: &str, amount: u64) -> Result<TransferOutcome, Error>
+{
+    if let Some(duplicate) = claim(&mut tx, bookkeeping, tx_hash, from, to, amount).await? {
+        return Ok(duplicate);
+    }
+
+    let from_row = sqlx::query!(
+        r#"
+        SELECT balance, version
+        FROM accounts
+        WHERE address = $1
+        "#,
+        from
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map(|row| (row.balance, row.version));
+
+    let Some((from_balance, from_version)) = from_row else {
+        tracing::info!("Account not found");
+        return Ok(TransferOutcome::Rejected { reason: Rejection::AccountNotFound(from.to_string()) });
+    };
+    history::read(from, from_balance, from_version);
+    checkpoint::reached(Checkpoint::AfterReadBalance).await?;
+
+    if from_balance < amount as i64 {
+        tracing::info!("Insufficient funds");
+        return Ok(TransferOutcome::Rejected { reason: Rejection::InsufficientFunds(from.to_string()) });
+    }
+
+    // Someone else committed a write to the sender since we read it, our balance check is stale.
+    // The bump_account_version trigger moves the version on
+    let new_from_row = sqlx::query!(
+        r#"
+        UPDATE accounts
+        SET balance = balance - $1, updated_at = now()
+        WHERE address = $2 AND version = $3
+        RETURNING balance, version
+        "#,
+        amount as i64,
+        from,
+        from_version
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map(|row| (row.balance, row.version));
+
+    let Some((new_from_balance, new_from_version)) = new_from_row else {
+        tracing::info!("Version conflict");
+        return Err(Error::Conflict(from.to_string()));
+    };
+    history::write(from, new_from_balance, new_from_version);
+    checkpoint::reached(Checkpoint::AfterDebit).await?;
+
+    // The credit does not depend on anything we read, but the trigger still bumps the version
+    // so that a concurrent debit of the recipient notices its balance changed
+    let new_to_balance = credit(&mut tx, to, amount).await?;
+
+    checkpoint::reached(Checkpoint::BeforeCommit).await?;
+    tx.commit().await?;
+    Ok(TransferOutcome::Applied { from_balance: new_from_balance, to_balance: new_to_balance })
+}
+
+/// Serializes transfers touching the same accounts with transaction scoped advisory locks,
+/// then runs the unmodified `bad_transfer` statements. Useful when the queries themselves
+/// cannot be changed to `FOR UPDATE`, e.g. when an ORM generates them
+pub async fn advisory_lock_transfer<'a>(mut tx: Transaction<'a, sqlx::Postgres>, bookkeeping: Bookkeeping, tx_hash: &str, from: &str, to: &str, amount: u64) -> Result<TransferOutcome, Error>
+{
+    // Always lock in address order, otherwise A->B and B->A can deadlock on each other
+    let mut addresses = [from, to];
+    addresses.sort_unstable();
+    let addresses = if from == to { &addresses[..1] } else { &addresses[..] };
+
+    for address in addresses {
+        // Released automatically at commit or rollback. Two addresses may share a hash,
+        // which only costs some extra serialization, never correctness
+        sqlx::query!(
+            r#"
+            SELECT pg_advisory_xact_lock(hashtext($1))
+            "#,
+            address
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    bad_transfer(tx, bookkeeping, tx_hash, from, to, amount).await
+}
+
+/// Like `pessimistic_transfer`, but locks both accounts up front and always in address order.
+/// `pessimistic_transfer` locks the sender first and the recipient on its UPDATE, so A->B and
+/// B->A running together each hold the lock the other one waits for
+pub async fn ordered_transfer<'a>(mut tx: Transaction<'a, sqlx::Postgres>, bookkeeping: Bookkeeping, tx_hash: &str, from: &str, to: &str, amount: u64) -> Result<TransferOutcome, Error>
+{
+    if let Some(duplicate) = claim(&mut tx, bookkeeping, tx_hash, from, to, amount).await? {
+        return Ok(duplicate);
+    }
+
+    let mut addresses = [from, to];
+    addresses.sort_unstable();
+
+    let mut from_balance = 0;
+    for address in addresses {
+        let row = sqlx::query!(
+            r#"
+            SELECT balance, version
+            FROM accounts
+            WHERE address = $1
+            FOR UPDATE
+            "#,
+            address
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .map(|row| (row.balance, row.version));
+
+        let Some((balance, version)) = row else {
+            tracing::info!("Account not found");
+            return Ok(TransferOutcome::Rejected { reason: Rejection::AccountNotFound(address.to_string()) });
+        };
+        history::read(address, balance, version);
+        if address == from {
+            from_balance = balance;
+        }
+    }
+    checkpoint::reached(Checkpoint::AfterReadBalance).await?;
+
+    if from_balance < amount as i64 {
+        tracing::info!("Insufficient funds");
+        return Ok(TransferOutcome::Rejected { reason: Rejection::InsufficientFunds(from.to_string()) });
+    }
+
+    let new_from_balance = debit(&mut tx, from, amount).await?;
+    let new_to_balance = credit(&mut tx, to, amount).await?;
+
+    checkpoint::reached(Checkpoint::BeforeCommit).await?;
+    tx.commit().await?;
+    Ok(TransferOutcome::Applied { from_balance: new_from_balance, to_balance: new_to_balance })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        fixtures::{accounts, balance, recorded},
+        verify::{account_balance_verify, ledger_consistency_verify},
+    };
+    use sqlx::{types::BigDecimal, PgPool};
+
+    fn insufficient_funds(address: &str) -> TransferOutcome {
+        TransferOutcome::Rejected { reason: Rejection::InsufficientFunds(address.to_string()) }
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_good_transfer(pool: PgPool) {
+        accounts(&pool, &[("0x0", 10), ("0x1", 10)]).await;
+        let tx = pool.begin().await.unwrap();
+        let outcome = good_transfer(tx, Bookkeeping::SingleEntry, "1", "0x0", "0x1", 3).await.unwrap();
+        assert_eq!(outcome, TransferOutcome::Applied { from_balance: 7, to_balance: 13 });
+        assert_eq!(account_balance_verify(&pool, "0x0").await.unwrap(), BigDecimal::from(7));
+        assert_eq!(account_balance_verify(&pool, "0x1").await.unwrap(), BigDecimal::from(13));
+
+        // The recipient is created when it does not exist yet
+        let tx = pool.begin().await.unwrap();
+        let outcome = good_transfer(tx, Bookkeeping::SingleEntry, "2", "0x0", "0x2", 3).await.unwrap();
+        assert_eq!(outcome, TransferOutcome::Applied { from_balance: 4, to_balance: 3 });
+        assert!(ledger_consistency_verify(&pool, Bookkeeping::SingleEntry).await.unwrap().is_consistent());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_good_transfer_rejections(pool: PgPool) {
+        accounts(&pool, &[("0x0", 10), ("0x1", 10)]).await;
+
+        let tx = pool.begin().await.unwrap();
+        let outcome = good_transfer(tx, Bookkeeping::SingleEntry, "1", "0x0", "0x1", 11).await.unwrap();
+        assert_eq!(outcome, insufficient_funds("0x0"));
+
+        let tx = pool.begin().await.unwrap();
+        let outcome = good_transfer(tx, Bookkeeping::SingleEntry, "2", "0x9", "0x1", 3).await.unwrap();
+        assert_eq!(outcome, TransferOutcome::Rejected { reason: Rejection::AccountNotFound("0x9".to_string()) });
+
+        assert_eq!(recorded(&pool).await, 0);
+        assert_eq!((balance(&pool, "0x0").await, balance(&pool, "0x1").await), (10, 10));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_bad_transfer(pool: PgPool) {
+        accounts(&pool, &[("0x0", 10), ("0x1", 10)]).await;
+        let tx = pool.begin().await.unwrap();
+        let outcome = bad_transfer(tx, Bookkeeping::DoubleEntry, "1", "0x0", "0x1", 3).await.unwrap();
+        assert_eq!(outcome, TransferOutcome::Applied { from_balance: 7, to_balance: 13 });
+        assert!(ledger_consistency_verify(&pool, Bookkeeping::SingleEntry).await.unwrap().is_consistent());
+        assert!(ledger_consistency_verify(&pool, Bookkeeping::DoubleEntry).await.unwrap().is_consistent());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_bad_transfer_rejections(pool: PgPool) {
+        accounts(&pool, &[("0x0", 10), ("0x1", 10)]).await;
+
+        let tx = pool.begin().await.unwrap();
+        let outcome = bad_transfer(tx, Bookkeeping::SingleEntry, "1", "0x0", "0x1", 11).await.unwrap();
+        assert_eq!(outcome, insufficient_funds("0x0"));
+
+        let tx = pool.begin().await.unwrap();
+        let outcome = bad_transfer(tx, Bookkeeping::SingleEntry, "2", "0x9", "0x1", 3).await.unwrap();
+        assert_eq!(outcome, TransferOutcome::Rejected { reason: Rejection::AccountNotFound("0x9".to_string()) });
+
+        // Unlike good_transfer, it does not create the recipient, the debit is rolled back
+        let tx = pool.begin().await.unwrap();
+        let res = bad_transfer(tx, Bookkeeping::SingleEntry, "3", "0x0", "0x9", 3).await;
+        assert!(matches!(res, Err(Error::Other(_))), "{:?}", res);
+
+        assert_eq!(recorded(&pool).await, 0);
+        assert_eq!((balance(&pool, "0x0").await, balance(&pool, "0x1").await), (10, 10));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_duplicate_tx_hash(pool: PgPool) {
+        accounts(&pool, &[("0x0", 10), ("0x1", 10)]).await;
+        let original = RecordedTransfer { tx_hash: "1".to_string(), from: "0x0".to_string(), to: "0x1".to_string(), amount: 3 };
+
+        let tx = pool.begin().await.unwrap();
+        good_transfer(tx, Bookkeeping::SingleEntry, "1", "0x0", "0x1", 3).await.unwrap();
+        // The replay is reported as the original, whatever it asked for
+        let tx = pool.begin().await.unwrap();
+        let outcome = good_transfer(tx, Bookkeeping::SingleEntry, "1", "0x1", "0x0", 5).await.unwrap();
+        assert_eq!(outcome, TransferOutcome::Duplicate { original: original.clone() });
+        let tx = pool.begin().await.unwrap();
+        let outcome = bad_transfer(tx, Bookkeeping::SingleEntry, "1", "0x0", "0x1", 3).await.unwrap();
+        assert_eq!(outcome, TransferOutcome::Duplicate { original });
+
+        assert_eq!(recorded(&pool).await, 1);
+        assert_eq!((balance(&pool, "0x0").await, balance(&pool, "0x1").await), (7, 13));
+    }
+}
@@ -0,0 +1,212 @@
+use std::fmt;
+
+use sqlx::{types::BigDecimal, Executor};
+
+use crate::{Bookkeeping, Error};
+
+/// An account whose balance does not match its initial balance plus its transactions
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountDiscrepancy {
+    pub address: String,
+    /// Initial balance plus debits minus credits
+    pub expected: BigDecimal,
+    pub actual: BigDecimal,
+    /// Total sent by the account
+    pub credit: BigDecimal,
+    /// Total received by the account
+    pub debit: BigDecimal,
+}
+
+/// Result of checking every account against the transaction log
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerificationReport {
+    /// Sum of all initial balances
+    pub supply_before: BigDecimal,
+    /// Sum of all balances after the run
+    pub supply_after: BigDecimal,
+    pub discrepancies: Vec<AccountDiscrepancy>,
+    /// Accounts that were overdrawn, with their balance
+    pub negative_balances: Vec<(String, i64)>,
+}
+
+impl VerificationReport {
+    /// No money was created or destroyed, every balance matches its transactions and none is negative
+    pub fn is_consistent(&self) -> bool {
+        self.supply_before == self.supply_after
+            && self.discrepancies.is_empty()
+            && self.negative_balances.is_empty()
+    }
+}
+
+impl fmt::Display for VerificationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Money supply: before {}, after {}", self.supply_before, self.supply_after)?;
+        writeln!(f, "Inconsistent accounts: {}", self.discrepancies.len())?;
+        for d in &self.discrepancies {
+            writeln!(
+                f,
+                "  {}: expected {}, actual {} (credit {}, debit {})",
+                d.address, d.expected, d.actual, d.credit, d.debit
+            )?;
+        }
+        writeln!(f, "Negative balances: {}", self.negative_balances.len())?;
+        for (address, balance) in &self.negative_balances {
+            writeln!(f, "  {}: {}", address, balance)?;
+        }
+        if self.is_consistent() {
+            write!(f, "Ledger consistent")
+        } else {
+            write!(f, "Ledger INCONSISTENT")
+        }
+    }
+}
+
+/// Balance stored for `address`, an error if there is no such account
+pub async fn account_balance_verify<'a, E>(executor: E, address: &str) -> Result<BigDecimal, Error>
+where E: Executor<'a, Database = sqlx::Postgres>
+{
+    let balance = sqlx::query!(
+        r#"
+        SELECT balance
+        FROM accounts
+        WHERE address = $1
+        "#,
+        address
+    )
+    .fetch_one(executor)
+    .await?
+    .balance;
+
+    Ok(BigDecimal::from(balance))
+}
+
+/// Rebuilds every balance from its initial balance plus its history and compares it with the
+/// stored balance. The history is the transaction log, or the postings with double-entry books
+pub async fn ledger_consistency_verify<'a, E>(executor: E, bookkeeping: Bookkeeping) -> Result<VerificationReport, Error>
+where E: Executor<'a, Database = sqlx::Postgres>
+{
+    let all = match bookkeeping {
+        Bookkeeping::SingleEntry => sqlx::query!(
+            r#"
+            select address,
+                balance,
+                initial_balance,
+                case when credit
+                    is null then 0
+                    else credit
+                end as credit,
+                case when debit
+                    is null then 0
+                    else debit
+                end as debit
+            from accounts,
+                LATERAL (
+                    select sum(amount) as credit
+                    from transaction
+                    where from_address = accounts.address
+                ) as credit,
+                LATERAL (
+                    select sum(amount) as debit
+                    from transaction
+                    where to_address = accounts.address
+                ) as debit
+            order by address;
+            "#
+        )
+        .fetch_all(executor)
+        .await?
+        .into_iter()
+        .map(|row| (row.address, row.balance, row.initial_balance, row.credit, row.debit))
+        .collect::<Vec<_>>(),
+        Bookkeeping::DoubleEntry => sqlx::query!(
+            r#"
+            select address,
+                balance,
+                initial_balance,
+                case when credit
+                    is null then 0
+                    else credit
+                end as credit,
+                case when debit
+                    is null then 0
+                    else debit
+                end as debit
+            from accounts,
+                LATERAL (
+                    select -sum(amount) filter (where amount < 0) as credit,
+                        sum(amount) filter (where amount > 0) as debit
+                    from postings
+                    where address = accounts.address
+                ) as postings
+            order by address;
+            "#
+        )
+        .fetch_all(executor)
+        .await?
+        .into_iter()
+        .map(|row| (row.address, row.balance, row.initial_balance, row.credit, row.debit))
+        .collect::<Vec<_>>(),
+    };
+
+    let mut report = VerificationReport {
+        supply_before: BigDecimal::from(0),
+        supply_after: BigDecimal::from(0),
+        discrepancies: Vec::new(),
+        negative_balances: Vec::new(),
+    };
+    for (address, balance, initial_balance, credit, debit) in all {
+        let actual = BigDecimal::from(balance);
+        let credit = credit.unwrap_or(BigDecimal::from(0));
+        let debit = debit.unwrap_or(BigDecimal::from(0));
+        let expected = initial_balance + &debit - &credit;
+
+        report.supply_before += initial_balance;
+        report.supply_after += &actual;
+        if balance < 0 {
+            report.negative_balances.push((address.clone(), balance));
+        }
+        if actual != expected {
+            report.discrepancies.push(AccountDiscrepancy {
+                address,
+                expected,
+                actual,
+                credit,
+                debit,
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{fixtures::accounts, transfer::good_transfer};
+    use sqlx::PgPool;
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_verifiers_report_discrepancies(pool: PgPool) {
+        accounts(&pool, &[("0x0", 10), ("0x1", 10)]).await;
+        let tx = pool.begin().await.unwrap();
+        good_transfer(tx, Bookkeeping::DoubleEntry, "1", "0x0", "0x1", 3).await.unwrap();
+        let report = ledger_consistency_verify(&pool, Bookkeeping::DoubleEntry).await.unwrap();
+        assert!(report.is_consistent(), "{}", report);
+
+        // Money that left 0x0 without a transaction, more than it had
+        sqlx::query!("UPDATE accounts SET balance = balance - 20 WHERE address = '0x0'").execute(&pool).await.unwrap();
+        assert_eq!(account_balance_verify(&pool, "0x0").await.unwrap(), BigDecimal::from(-13));
+        for bookkeeping in [Bookkeeping::SingleEntry, Bookkeeping::DoubleEntry] {
+            let report = ledger_consistency_verify(&pool, bookkeeping).await.unwrap();
+            assert!(!report.is_consistent());
+            assert_eq!((report.supply_before.clone(), report.supply_after.clone()), (BigDecimal::from(20), BigDecimal::from(0)));
+            assert_eq!(report.discrepancies.len(), 1);
+            assert_eq!(report.discrepancies[0].address, "0x0");
+            assert_eq!(report.discrepancies[0].expected, BigDecimal::from(7));
+            assert_eq!(report.negative_balances, vec![("0x0".to_string(), -13)]);
+        }
+
+        let missing = account_balance_verify(&pool, "0x9").await;
+        assert!(matches!(missing, Err(Error::Sqlx(sqlx::Error::RowNotFound))), "{:?}", missing);
+    }
+}
@@ -0,0 +1,107 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Distribution, Zipf};
+
+/// Which (from, to) account pairs the transfers of a run use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Workload {
+    /// Every transfer debits `0x0`, the recipient cycles through all accounts
+    HotSender,
+    /// Random pairs, every odd transfer reverses the previous pair so that
+    /// A->B and B->A are in flight at the same time
+    Bidirectional,
+    /// Sender and recipient drawn uniformly
+    Uniform,
+    /// Senders drawn from a Zipf distribution, `0x0` being the most popular, recipients uniformly
+    Zipf,
+    /// Every transfer pays `0x0`, the senders are drawn uniformly from the other accounts
+    FanIn,
+}
+
+/// Generates the pairs of a workload. The `i`-th pair only depends on the seed and `i`,
+/// so a run can be replayed exactly whatever order its transfers get scheduled in
+#[derive(Debug, Clone)]
+pub struct PairGenerator {
+    workload: Workload,
+    accounts: u64,
+    seed: u64,
+    zipf: Zipf<f64>,
+}
+
+impl PairGenerator {
+    /// `accounts` must be at least 2, `zipf_exponent` non-negative and is only used by `Workload::Zipf`
+    pub fn new(workload: Workload, accounts: u64, seed: u64, zipf_exponent: f64) -> Result<Self, String> {
+        if accounts < 2 {
+            return Err(format!("a workload needs at least 2 accounts, got {}", accounts));
+        }
+        let zipf = Zipf::new(accounts, zipf_exponent).map_err(|e| format!("zipf exponent {}: {}", zipf_exponent, e))?;
+        Ok(PairGenerator { workload, accounts, seed, zipf })
+    }
+
+    /// Returns the account indexes of the `i`-th transfer
+    pub fn pair(&self, i: u64) -> (u64, u64) {
+        match self.workload {
+            Workload::HotSender => (0, i % self.accounts),
+            Workload::Bidirectional => {
+                // Seed both halves of a pair identically so they mirror each other
+                let mut rng = self.rng(i / 2);
+                let a = rng.gen_range(0..self.accounts);
+                let b = self.other(&mut rng, a);
+                if i % 2 == 1 { (b, a) } else { (a, b) }
+            }
+            Workload::Uniform => {
+                let mut rng = self.rng(i);
+                let a = rng.gen_range(0..self.accounts);
+                (a, self.other(&mut rng, a))
+            }
+            Workload::Zipf => {
+                let mut rng = self.rng(i);
+                // Ranks start at 1
+                let a = self.zipf.sample(&mut rng) as u64 - 1;
+                (a, self.other(&mut rng, a))
+            }
+            Workload::FanIn => {
+                let mut rng = self.rng(i);
+                (rng.gen_range(1..self.accounts), 0)
+            }
+        }
+    }
+
+    fn rng(&self, stream: u64) -> StdRng {
+        StdRng::seed_from_u64(self.seed ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+    }
+
+    /// A uniformly drawn account other than `a`
+    fn other(&self, rng: &mut StdRng, a: u64) -> u64 {
+        (a + rng.gen_range(1..self.accounts)) % self.accounts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pairs_are_reproducible() {
+        for workload in [Workload::HotSender, Workload::Bidirectional, Workload::Uniform, Workload::Zipf, Workload::FanIn] {
+            let a = PairGenerator::new(workload, 10, 42, 1.2).unwrap();
+            let b = PairGenerator::new(workload, 10, 42, 1.2).unwrap();
+            for i in (0..1000).rev() {
+                let (from, to) = a.pair(i);
+                assert_eq!((from, to), b.pair(i));
+                assert!(from < 10 && to < 10);
+                if workload != Workload::HotSender {
+                    assert_ne!(from, to);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_zipf_skews_towards_first_account() {
+        let generator = PairGenerator::new(Workload::Zipf, 100, 7, 1.5).unwrap();
+        let hot = (0..1000).filter(|&i| generator.pair(i).0 == 0).count();
+        let cold = (0..1000).filter(|&i| generator.pair(i).0 == 99).count();
+        assert!(hot > 10 * cold.max(1));
+    }
+}